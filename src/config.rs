@@ -2,11 +2,215 @@ use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use derive_more::Display;
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "ca_file: {:?}, insecure: {}", ca_file, insecure)]
+pub(crate) struct Tls {
+    pub(crate) ca_file: Option<String>,
+    pub(crate) client_cert: Option<String>,
+    pub(crate) client_key: Option<String>,
+    #[serde(default)]
+    pub(crate) insecure: bool,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
 #[display(fmt = "url: {}", url)]
 pub(crate) struct HomeAssistant {
+    #[serde(default)]
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) token: String,
+    pub(crate) token_file: Option<String>,
+    pub(crate) event: Option<String>,
+    pub(crate) resync_interval: Option<u64>,
+    pub(crate) entity_id: Option<String>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "host: {}, port: {:?}", host, port)]
+pub(crate) struct Mqtt {
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) client_id: Option<String>,
+    pub(crate) topic_prefix: Option<String>,
+    pub(crate) discovery_prefix: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "url: {}", url)]
+pub(crate) struct HomeAssistantWebsocket {
+    #[serde(default)]
     pub(crate) url: String,
+    #[serde(default)]
     pub(crate) token: String,
+    pub(crate) token_file: Option<String>,
+    pub(crate) event_type: Option<String>,
+    pub(crate) entity_id: Option<String>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "service: {}", service)]
+pub(crate) struct ServiceCall {
+    pub(crate) service: String,
+    #[serde(default)]
+    pub(crate) data: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "url: {}, on: {}, off: {:?}", url, on, off)]
+pub(crate) struct HomeAssistantService {
+    #[serde(default)]
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) token: String,
+    pub(crate) token_file: Option<String>,
+    pub(crate) on: ServiceCall,
+    pub(crate) off: Option<ServiceCall>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "url: {}, method: {:?}", url, method)]
+pub(crate) struct Webhook {
+    pub(crate) url: String,
+    pub(crate) method: Option<String>,
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Option<serde_json::Value>,
+    pub(crate) secret: Option<String>,
+    pub(crate) signature_header: Option<String>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "on_grant: {:?}, on_deny: {:?}, on_release: {:?}", on_grant, on_deny, on_release)]
+pub(crate) struct Command {
+    pub(crate) on_grant: Option<Vec<String>>,
+    pub(crate) on_deny: Option<Vec<String>>,
+    pub(crate) on_release: Option<Vec<String>>,
+    pub(crate) timeout: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "url: {}, channel: {:?}, generation: {:?}", url, channel, generation)]
+pub(crate) struct Shelly {
+    pub(crate) url: String,
+    pub(crate) channel: Option<u8>,
+    pub(crate) generation: Option<u8>,
+    pub(crate) auto_off: Option<u64>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "url: {}, relay: {:?}", url, relay)]
+pub(crate) struct Tasmota {
+    pub(crate) url: String,
+    pub(crate) relay: Option<u8>,
+    pub(crate) auto_off: Option<u64>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) tls: Option<Tls>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "host: {}, port: {:?}, unit_id: {:?}", host, port, unit_id)]
+pub(crate) struct Modbus {
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+    pub(crate) unit_id: Option<u8>,
+    pub(crate) coil: Option<u16>,
+    #[serde(default)]
+    pub(crate) coils: HashMap<String, u16>,
+    pub(crate) timeout: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "serial: {:?}, listen: {:?}, address: {:?}", serial, listen, address)]
+pub(crate) struct Osdp {
+    pub(crate) serial: Option<String>,
+    pub(crate) baud_rate: Option<u32>,
+    pub(crate) listen: Option<String>,
+    pub(crate) address: Option<u8>,
+    pub(crate) facility_code: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum TriggerKind {
+    HomeAssistant(HomeAssistant),
+    Mqtt(Mqtt),
+    HomeAssistantWebsocket(HomeAssistantWebsocket),
+    HomeAssistantService(HomeAssistantService),
+    Webhook(Webhook),
+    Command(Command),
+    Shelly(Shelly),
+    Tasmota(Tasmota),
+    Modbus(Modbus),
+    Osdp(Osdp),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "name: {}, kind: {:?}", name, kind)]
+pub(crate) struct Trigger {
+    pub(crate) name: String,
+    #[serde(flatten)]
+    pub(crate) kind: TriggerKind,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "entity: {:?}, vacation_allow: [{:?}]", entity, vacation_allow)]
+pub(crate) struct Modes {
+    #[serde(default)]
+    pub(crate) vacation_allow: Vec<String>,
+    pub(crate) entity: Option<String>,
+    pub(crate) poll_interval: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "latitude: {}, longitude: {}", latitude, longitude)]
+pub(crate) struct Location {
+    pub(crate) latitude: f64,
+    pub(crate) longitude: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    Entry,
+    Exit,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "duration_ms: {}, cooldown: {:?}", duration_ms, cooldown)]
+pub(crate) struct Pulse {
+    pub(crate) duration_ms: u64,
+    pub(crate) cooldown: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
+#[display(fmt = "adapter: {:?}, cutoff_rssi: {:?}, entity: {:?}", adapter, cutoff_rssi, entity)]
+pub(crate) struct Zone {
+    pub(crate) adapter: Option<String>,
+    pub(crate) cutoff_rssi: Option<i16>,
+    pub(crate) entity: Option<String>,
+    pub(crate) allowed_times: Option<HashMap<String, Vec<String>>>,
+    pub(crate) time_zone: Option<String>,
+    pub(crate) direction: Option<Direction>,
+    #[serde(default)]
+    pub(crate) anti_passback: bool,
+    pub(crate) anti_passback_grace: Option<u64>,
+    pub(crate) min_persons: Option<usize>,
+    pub(crate) min_persons_window: Option<u64>,
+    pub(crate) triggers: Option<Vec<String>>,
+    #[serde(default)]
+    pub(crate) conditions: HashMap<String, String>,
+    pub(crate) pulse: Option<Pulse>,
+    pub(crate) failover: Option<Vec<String>>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
@@ -18,13 +222,37 @@ pub(crate) struct Device {
     pub(crate) manufacture: u16,
     pub(crate) cutoff_rssi: i16,
     pub(crate) allowed_times: HashMap<String, Vec<String>>,
+    pub(crate) max_uses: Option<u32>,
+    #[serde(default)]
+    pub(crate) max_uses_per_day: bool,
+    pub(crate) uses_cooldown: Option<u64>,
+    pub(crate) time_zone: Option<String>,
+    pub(crate) zones: Option<Vec<String>>,
+    pub(crate) triggers: Option<Vec<String>>,
+    pub(crate) service_data: Option<HashMap<String, serde_json::Value>>,
+    pub(crate) hold_time: Option<u64>,
+    pub(crate) absence_timeout: Option<u64>,
+    pub(crate) pulse: Option<Pulse>,
+    pub(crate) entity_id: Option<String>,
+    pub(crate) device_class: Option<String>,
+    pub(crate) icon: Option<String>,
+    pub(crate) credential: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Display, Clone)]
-#[display(fmt = "home_assistant: {}, allowed_skew: {}, devices: [{:?}]", home_assistant, allowed_skew, devices)]
+#[display(fmt = "home_assistant: {:?}, allowed_skew: {}, devices: [{:?}]", home_assistant, allowed_skew, devices)]
 pub(crate) struct Config {
     pub(crate) database_path: String,
-    pub(crate) home_assistant: HomeAssistant,
+    pub(crate) home_assistant: Option<HomeAssistant>,
+    #[serde(default)]
+    pub(crate) triggers: Vec<Trigger>,
     pub(crate) allowed_skew: u32,
+    pub(crate) absence_timeout: Option<u64>,
+    pub(crate) time_zone: Option<String>,
+    pub(crate) location: Option<Location>,
+    pub(crate) modes: Option<Modes>,
+    pub(crate) control_socket: Option<String>,
+    #[serde(default)]
+    pub(crate) zones: HashMap<String, Zone>,
     pub(crate) devices: HashMap<String, Device>,
 }
@@ -0,0 +1,98 @@
+//! Control socket to manipulate the running controller.
+//!
+//! Accepts one command per line and answers with one line:
+//!
+//! * `mode` returns the current mode
+//! * `mode <normal|lockdown|vacation>` switches the mode
+//! * `presence <device>` returns if the device is inside or outside
+//! * `presence <device> <inside|outside>` marks the device, e.g. after a door event
+//! * `failovers` returns the latest deliveries to fallback triggers
+
+use log::{info, warn};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}};
+
+use crate::{error, config, database, mode, presence};
+
+/// Failovers returned by the `failovers` command
+const FAILOVERS_LIMIT: u32 = 20;
+
+async fn handle_command(config: &config::Config, line: &str) -> error::Result<String> {
+    let mut splits = line.split_whitespace();
+
+    match (splits.next(), splits.next(), splits.next()) {
+        (Some("mode"), None, None) => {
+            let current = database::get_mode(config.database_path.clone()).await?;
+            Ok(current.to_string())
+        }
+        (Some("mode"), Some(new_mode), None) => {
+            mode::set_mode(config.database_path.clone(), new_mode.parse()?).await?;
+            Ok("ok".to_string())
+        }
+        (Some("presence"), Some(device), None) => {
+            let current = presence::get_presence(config.database_path.clone(), device.to_string()).await?;
+            Ok(current.to_string())
+        }
+        (Some("presence"), Some(device), Some(new_presence)) => {
+            presence::set_presence(config.database_path.clone(), device.to_string(), presence::Presence::parse(new_presence)?).await?;
+            Ok("ok".to_string())
+        }
+        (Some("failovers"), None, None) => {
+            let failovers = database::get_failovers(config.database_path.clone(), FAILOVERS_LIMIT).await?;
+            let lines: Vec<String> = failovers.iter()
+                .map(|failover| format!("{} {} {} {} {}->{}", failover.time, failover.zone, failover.device, failover.outcome, failover.primary_backend, failover.backend))
+                .collect();
+
+            Ok(if lines.is_empty() { "none".to_string() } else { lines.join("; ") })
+        }
+        _ => Err(error::new(format!("unknown command: {}", line))),
+    }
+}
+
+async fn handle_connection(config: config::Config, stream: UnixStream) -> error::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let answer = match handle_command(&config, &line).await {
+            Ok(answer) => answer,
+            Err(err) => format!("error: {}", err),
+        };
+
+        writer.write_all(format!("{}\n", answer).as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn start(config: config::Config) -> error::Result<()> {
+    let path = match config.control_socket.clone() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    // Remove a stale socket from an earlier run
+    if std::path::Path::new(&path).exists() {
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    info!("Listening for control commands on {}", path);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => {
+                    let config = config.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(config, stream).await {
+                            warn!("Control connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Could not accept control connection: {}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -1,15 +1,43 @@
 use rusqlite::{Connection, params};
 
-use crate::{error, config};
+use crate::{error, config, mode};
 
 pub(crate) struct TimeDTO {
     pub(crate) last_seen_local: u64,
     pub(crate) last_seen: u32,
 }
 
+pub(crate) struct UsesDTO {
+    pub(crate) counter: u32,
+    pub(crate) last_used: u64,
+    pub(crate) day: String,
+}
+
+pub(crate) struct PresenceDTO {
+    pub(crate) inside: bool,
+    pub(crate) zone: String,
+    pub(crate) changed: u64,
+}
+
+pub(crate) struct OutboxDTO {
+    pub(crate) backend: String,
+    pub(crate) entity: String,
+    pub(crate) decision: String,
+    pub(crate) attempts: u32,
+}
+
+pub(crate) struct FailoverDTO {
+    pub(crate) time: u64,
+    pub(crate) zone: String,
+    pub(crate) device: String,
+    pub(crate) outcome: String,
+    pub(crate) primary_backend: String,
+    pub(crate) backend: String,
+}
+
 pub(crate) async fn init_database(config: &mut config::Config) -> error::Result<()> {
     let conn = Connection::open(config.database_path.clone())
-        .or_else(|err| Err(error::new(format!("could not open fencer.db: {:?}", err).to_string())))?;
+        .map_err(|err| error::new(format!("could not open fencer.db: {:?}", err)))?;
 
     conn.execute("CREATE TABLE IF NOT EXISTS timestamps (device TEXT PRIMARY KEY, last_seen_local INTEGER, last_seen INTEGER)", [])
         .or(Err(error::new("could not create timestamps table".to_string())))?;
@@ -17,6 +45,21 @@ pub(crate) async fn init_database(config: &mut config::Config) -> error::Result<
     conn.execute("CREATE TABLE IF NOT EXISTS restarts (device TEXT PRIMARY KEY, counter INTEGER)", [])
         .or(Err(error::new("could not create timestamps table".to_string())))?;
 
+    conn.execute("CREATE TABLE IF NOT EXISTS uses (device TEXT PRIMARY KEY, counter INTEGER, last_used INTEGER, day TEXT)", [])
+        .or(Err(error::new("could not create uses table".to_string())))?;
+
+    conn.execute("CREATE TABLE IF NOT EXISTS mode (id INTEGER PRIMARY KEY CHECK (id = 0), mode TEXT)", [])
+        .or(Err(error::new("could not create mode table".to_string())))?;
+
+    conn.execute("CREATE TABLE IF NOT EXISTS presence (device TEXT PRIMARY KEY, inside INTEGER, zone TEXT, changed INTEGER)", [])
+        .or(Err(error::new("could not create presence table".to_string())))?;
+
+    conn.execute("CREATE TABLE IF NOT EXISTS outbox (backend TEXT, entity TEXT, decision TEXT, attempts INTEGER, next_attempt INTEGER, PRIMARY KEY (backend, entity))", [])
+        .or(Err(error::new("could not create outbox table".to_string())))?;
+
+    conn.execute("CREATE TABLE IF NOT EXISTS failovers (id INTEGER PRIMARY KEY AUTOINCREMENT, time INTEGER, zone TEXT, device TEXT, outcome TEXT, primary_backend TEXT, backend TEXT)", [])
+        .or(Err(error::new("could not create failovers table".to_string())))?;
+
     conn.close()
         .or(Err(error::new("Could not close database".to_string())))?;
 
@@ -91,4 +134,279 @@ pub(crate) async fn store_restarts(database_path: String, device: String, counte
         .or(Err(error::new("Could not close database".to_string())))?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub(crate) async fn get_uses(database_path: String, device: String) -> error::Result<UsesDTO> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    let usesdto_obj: rusqlite::Result<UsesDTO> = conn.query_row("SELECT counter, last_used, day FROM uses WHERE device = ?1", 
+        params![device], |row| {
+            let counter = row.get(0)?;
+            let last_used = row.get(1)?;
+            let day = row.get(2)?;
+
+            Ok(UsesDTO {
+                counter,
+                last_used,
+                day,
+            })
+        });
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(usesdto_obj.unwrap_or({
+        UsesDTO {
+            counter: 0,
+            last_used: 0,
+            day: String::new(),
+        }
+    }))
+}
+
+pub(crate) async fn store_uses(database_path: String, device: String, counter: u32, last_used: u64, day: String) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("INSERT OR REPLACE INTO uses(device, counter, last_used, day) VALUES (?1, ?2, ?3, ?4)", 
+        params![device, counter, last_used, day])
+        .or(Err(error::new("could not insert or replace new uses counter".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+pub(crate) async fn get_mode(database_path: String) -> error::Result<mode::Mode> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    let mode: rusqlite::Result<String> = conn.query_row("SELECT mode FROM mode WHERE id = 0", 
+        [], |row| {
+            let mode = row.get(0)?;
+            Ok(mode)
+        });
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    match mode {
+        Ok(mode) => mode.parse(),
+        Err(_err) => Ok(mode::Mode::Normal),
+    }
+}
+
+pub(crate) async fn store_mode(database_path: String, mode: mode::Mode) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("INSERT OR REPLACE INTO mode(id, mode) VALUES (0, ?1)", 
+        params![mode.to_string()])
+        .or(Err(error::new("could not insert or replace new mode".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+pub(crate) async fn get_presence(database_path: String, device: String) -> error::Result<PresenceDTO> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    let presencedto_obj: rusqlite::Result<PresenceDTO> = conn.query_row("SELECT inside, zone, changed FROM presence WHERE device = ?1", 
+        params![device], |row| {
+            let inside = row.get(0)?;
+            let zone = row.get(1)?;
+            let changed = row.get(2)?;
+
+            Ok(PresenceDTO {
+                inside,
+                zone,
+                changed,
+            })
+        });
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(presencedto_obj.unwrap_or({
+        PresenceDTO {
+            inside: false,
+            zone: String::new(),
+            changed: 0,
+        }
+    }))
+}
+
+pub(crate) async fn store_presence(database_path: String, device: String, inside: bool, zone: String, changed: u64) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("INSERT OR REPLACE INTO presence(device, inside, zone, changed) VALUES (?1, ?2, ?3, ?4)", 
+        params![device, inside, zone, changed])
+        .or(Err(error::new("could not insert or replace new presence".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+/// Queues the latest decision, a row which is already retried keeps its backoff
+pub(crate) async fn queue_outbox(database_path: String, backend: String, entity: String, decision: String, next_attempt: u64) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("INSERT INTO outbox(backend, entity, decision, attempts, next_attempt) VALUES (?1, ?2, ?3, 0, ?4) ON CONFLICT(backend, entity) DO UPDATE SET decision = excluded.decision", 
+        params![backend, entity, decision, next_attempt])
+        .or(Err(error::new("could not queue outbox entry".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+/// Schedules the next retry, unless the decision was replaced or delivered meanwhile
+pub(crate) async fn reschedule_outbox(database_path: String, entry: OutboxDTO, attempts: u32, next_attempt: u64) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("UPDATE outbox SET attempts = ?1, next_attempt = ?2 WHERE backend = ?3 AND entity = ?4 AND decision = ?5", 
+        params![attempts, next_attempt, entry.backend, entry.entity, entry.decision])
+        .or(Err(error::new("could not reschedule outbox entry".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+pub(crate) async fn get_due_outbox(database_path: String, now: u64) -> error::Result<Vec<OutboxDTO>> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    let entries = {
+        let mut stmt = conn.prepare("SELECT backend, entity, decision, attempts FROM outbox WHERE next_attempt <= ?1 ORDER BY next_attempt")
+            .or(Err(error::new("could not prepare outbox query".to_string())))?;
+
+        let rows = stmt.query_map(params![now], |row| {
+            let backend = row.get(0)?;
+            let entity = row.get(1)?;
+            let decision = row.get(2)?;
+            let attempts = row.get(3)?;
+
+            Ok(OutboxDTO {
+                backend,
+                entity,
+                decision,
+                attempts,
+            })
+        }).or(Err(error::new("could not query outbox".to_string())))?;
+
+        rows.collect::<rusqlite::Result<Vec<OutboxDTO>>>()
+            .or(Err(error::new("could not read outbox entry".to_string())))?
+    };
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(entries)
+}
+
+pub(crate) async fn delete_outbox(database_path: String, backend: String, entity: String) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("DELETE FROM outbox WHERE backend = ?1 AND entity = ?2", 
+        params![backend, entity])
+        .or(Err(error::new("could not delete outbox entry".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+/// Deletes the retried entry, unless it was replaced by a newer decision meanwhile
+pub(crate) async fn delete_retried_outbox(database_path: String, entry: OutboxDTO) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("DELETE FROM outbox WHERE backend = ?1 AND entity = ?2 AND decision = ?3", 
+        params![entry.backend, entry.entity, entry.decision])
+        .or(Err(error::new("could not delete outbox entry".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+pub(crate) async fn store_failover(database_path: String, failover: FailoverDTO) -> error::Result<()> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    conn.execute("INSERT INTO failovers(time, zone, device, outcome, primary_backend, backend) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", 
+        params![failover.time, failover.zone, failover.device, failover.outcome, failover.primary_backend, failover.backend])
+        .or(Err(error::new("could not insert failover".to_string())))?;
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(())
+}
+
+pub(crate) async fn get_failovers(database_path: String, limit: u32) -> error::Result<Vec<FailoverDTO>> {
+    let conn = Connection::open(database_path)
+        .or(Err(error::new("could not open fencer.db".to_string())))?;
+
+    let failovers = {
+        let mut stmt = conn.prepare("SELECT time, zone, device, outcome, primary_backend, backend FROM failovers ORDER BY id DESC LIMIT ?1")
+            .or(Err(error::new("could not prepare failovers query".to_string())))?;
+
+        let rows = stmt.query_map(params![limit], |row| {
+            let time = row.get(0)?;
+            let zone = row.get(1)?;
+            let device = row.get(2)?;
+            let outcome = row.get(3)?;
+            let primary_backend = row.get(4)?;
+            let backend = row.get(5)?;
+
+            Ok(FailoverDTO {
+                time,
+                zone,
+                device,
+                outcome,
+                primary_backend,
+                backend,
+            })
+        }).or(Err(error::new("could not query failovers".to_string())))?;
+
+        rows.collect::<rusqlite::Result<Vec<FailoverDTO>>>()
+            .or(Err(error::new("could not read failover".to_string())))?
+    };
+
+    conn.close()
+        .or(Err(error::new("Could not close database".to_string())))?;
+
+    Ok(failovers)
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates an empty database in the temp directory
+    pub(crate) async fn create(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ble-fencer-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut config: config::Config = serde_yaml::from_str(&format!("database_path: {}\nallowed_skew: 30\ndevices: {{}}", path.display())).unwrap();
+        init_database(&mut config).await.unwrap();
+
+        config.database_path
+    }
+}
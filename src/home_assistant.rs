@@ -0,0 +1,152 @@
+//! Resolves and checks how Home Assistant is reached.
+//!
+//! Running as a Home Assistant add-on, the API is reached through the Supervisor
+//! proxy with the `SUPERVISOR_TOKEN` from the environment, so `url` and `token`
+//! can be left out. Tokens can also be read from a `token_file`. Outside of the
+//! add-on an empty top level `url` disables Home Assistant, while triggers
+//! without a url are rejected.
+
+use std::{env, fs};
+
+use log::{info, warn};
+
+use crate::{config, error, trigger};
+
+const SUPERVISOR_TOKEN: &str = "SUPERVISOR_TOKEN";
+const SUPERVISOR_URL: &str = "http://supervisor/core/api/";
+const SUPERVISOR_WEBSOCKET_URL: &str = "ws://supervisor/core/websocket";
+
+fn resolve(url: &mut String, token: &mut String, token_file: &Option<String>, supervisor_url: &str) -> error::Result<()> {
+    if token.is_empty() {
+        if let Some(token_file) = token_file {
+            *token = fs::read_to_string(token_file)
+                .map_err(|e| error::new(format!("could not read home assistant token from {}: {}", token_file, e)))?
+                .trim()
+                .to_string();
+        }
+    }
+
+    let supervisor_token = env::var(SUPERVISOR_TOKEN).ok();
+
+    if url.is_empty() {
+        if supervisor_token.is_none() {
+            return Err(error::new(format!("home assistant url is missing and {} is not set", SUPERVISOR_TOKEN)));
+        }
+
+        info!("Using the Home Assistant Supervisor at {}", supervisor_url);
+        *url = supervisor_url.to_string();
+    }
+
+    if token.is_empty() {
+        *token = supervisor_token
+            .ok_or(error::new(format!("home assistant token for {} is missing", url)))?;
+    }
+
+    reqwest::Url::parse(url)
+        .map_err(|e| error::new(format!("invalid home assistant url {}: {}", url, e)))?;
+
+    Ok(())
+}
+
+fn resolve_api(url: &mut String, token: &mut String, token_file: &Option<String>) -> error::Result<()> {
+    resolve(url, token, token_file, SUPERVISOR_URL)?;
+
+    // Paths are appended to the API url
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+
+    Ok(())
+}
+
+async fn check(url: &str, token: &str, client: reqwest::Client) -> error::Result<()> {
+    let res = client.get(url)
+        .bearer_auth(token)
+        .send()
+        .await;
+
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            // Home Assistant might just be restarting, deliveries are retried
+            warn!("Could not reach home assistant at {}: {}", url, err);
+            return Ok(());
+        }
+    };
+
+    match res.status().as_u16() {
+        401 | 403 => Err(error::new(format!("home assistant at {} rejected the token", url))),
+        404 => Err(error::new(format!("{} is not the home assistant API, it usually ends with /api/", url))),
+        _ => Ok(()),
+    }
+}
+
+/// Fills in urls and tokens of all Home Assistant connections and checks them
+pub(crate) async fn validate(config: &mut config::Config, clients: &mut trigger::HttpClients) -> error::Result<()> {
+    let mut apis = Vec::new();
+
+    // The default config ships an empty top level url, which only works as an add-on
+    let unconfigured = config.home_assistant.as_ref()
+        .is_some_and(|home_assistant| home_assistant.url.is_empty() && env::var(SUPERVISOR_TOKEN).is_err());
+    if unconfigured {
+        warn!("Home Assistant url is empty and {} is not set, not publishing to Home Assistant", SUPERVISOR_TOKEN);
+        config.home_assistant = None;
+    }
+
+    if let Some(home_assistant) = &mut config.home_assistant {
+        resolve_api(&mut home_assistant.url, &mut home_assistant.token, &home_assistant.token_file)?;
+        apis.push((home_assistant.url.clone(), home_assistant.token.clone(), home_assistant.tls.clone()));
+    }
+
+    for trigger in config.triggers.iter_mut() {
+        match &mut trigger.kind {
+            config::TriggerKind::HomeAssistant(home_assistant) => {
+                resolve_api(&mut home_assistant.url, &mut home_assistant.token, &home_assistant.token_file)?;
+                apis.push((home_assistant.url.clone(), home_assistant.token.clone(), home_assistant.tls.clone()));
+            }
+            config::TriggerKind::HomeAssistantService(service) => {
+                resolve_api(&mut service.url, &mut service.token, &service.token_file)?;
+                apis.push((service.url.clone(), service.token.clone(), service.tls.clone()));
+            }
+            config::TriggerKind::HomeAssistantWebsocket(websocket) => {
+                resolve(&mut websocket.url, &mut websocket.token, &websocket.token_file, SUPERVISOR_WEBSOCKET_URL)?;
+            }
+            _ => (),
+        }
+    }
+
+    apis.dedup();
+    for (url, token, tls) in apis {
+        check(&url, &token, clients.get(&tls)?).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn default_config_starts_outside_of_the_add_on() {
+        if env::var(SUPERVISOR_TOKEN).is_ok() {
+            return;
+        }
+
+        let mut config: config::Config = serde_yaml::from_str(include_str!("../default/config.yaml")).unwrap();
+
+        validate(&mut config, &mut trigger::HttpClients::default()).await.unwrap();
+        assert_eq!(config.home_assistant, None);
+    }
+
+    #[tokio::test]
+    async fn triggers_need_a_url_outside_of_the_add_on() {
+        if env::var(SUPERVISOR_TOKEN).is_ok() {
+            return;
+        }
+
+        let mut config: config::Config = serde_yaml::from_str("database_path: fencer.db\nallowed_skew: 30\ndevices: {}\ntriggers:\n  - name: service\n    type: home_assistant_service\n    token: secret\n    on:\n      service: lock.unlock").unwrap();
+
+        assert!(validate(&mut config, &mut trigger::HttpClients::default()).await.is_err());
+    }
+}
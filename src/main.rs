@@ -4,18 +4,29 @@ mod config;
 mod trigger;
 mod error;
 mod database;
+mod schedule;
+mod sun;
+mod mode;
+mod control;
+mod zone;
+mod presence;
+mod state;
+mod home_assistant;
 
 use aes::{Aes128, cipher::{KeyInit, generic_array::GenericArray, BlockDecrypt, typenum}};
 use bluer::{Adapter, AdapterEvent, Address};
 use byteorder::ByteOrder;
-use chrono::{Datelike, Weekday};
+use chrono_tz::Tz;
 use clap::Parser;
-use futures::{pin_mut, StreamExt};
+use futures::StreamExt;
 use hex::FromHex;
 use core::time;
-use std::fs;
+use std::{collections::HashMap, fs, sync::Arc};
 use log::{debug, error, info, warn};
 
+/// Milliseconds between checks for ended pulses and absent devices
+const TIMER_INTERVAL: u64 = 100;
+
 async fn get_from_hex_array(str: &str) -> error::Result<Vec<u8>> {
     let splits = str.split(", ");
     let mut arr: Vec<u8> = Vec::new();
@@ -24,7 +35,7 @@ async fn get_from_hex_array(str: &str) -> error::Result<Vec<u8>> {
         let hex_str = &s[2..4];
         let a = Vec::from_hex(hex_str)
             .or(Err(error::new("could not convert from hex".to_string())))?;
-        let val = a.get(0)
+        let val = a.first()
             .ok_or(error::new("hex array has no 0 index".to_string()))?;
         arr.push(*val);
     }
@@ -32,47 +43,71 @@ async fn get_from_hex_array(str: &str) -> error::Result<Vec<u8>> {
     Ok(arr)
 }
 
-async fn get_time(str: &str) -> error::Result<chrono::NaiveTime> {
-    let mut splits = str.split(":");
+/// Default seconds in which repeated grants count as the same use
+const DEFAULT_USES_COOLDOWN: u64 = 60;
+
+/// Returns the uses counted so far and if the last use is still going on
+async fn get_used(database_path: String, formated_addr: String, device_config: &config::Device, current_time: &chrono::DateTime<Tz>) -> error::Result<(u32, bool)> {
+    let now = current_time.timestamp() as u64;
+    let today = current_time.date_naive().to_string();
+    let mut uses = database::get_uses(database_path, formated_addr).await?;
+
+    // Per day passes start over every day
+    if device_config.max_uses_per_day && uses.day != today {
+        uses.counter = 0;
+    }
 
-    let hours = splits.next()
-        .unwrap()
-        .parse::<u32>()
-        .or(Err(error::new("could not parse hours to int".to_string())))?;
+    // Advertisements within the cooldown still belong to the last use
+    let cooldown = device_config.uses_cooldown.unwrap_or(DEFAULT_USES_COOLDOWN);
+    let ongoing = uses.counter > 0 && now.saturating_sub(uses.last_used) < cooldown;
 
-    let minutes = splits.next()
-        .unwrap()
-        .parse::<u32>()
-        .or(Err(error::new("could not parse minutes to int".to_string())))?;
+    Ok((uses.counter, ongoing))
+}
+
+async fn has_use(database_path: String, formated_addr: String, device_config: &config::Device, current_time: &chrono::DateTime<Tz>) -> error::Result<bool> {
+    let max_uses = match device_config.max_uses {
+        Some(max_uses) => max_uses,
+        None => return Ok(true),
+    };
 
-    let seconds = splits.next()
-        .unwrap()
-        .parse::<u32>()
-        .or(Err(error::new("could not parse seconds to int".to_string())))?;
+    let (counter, ongoing) = get_used(database_path, formated_addr, device_config, current_time).await?;
+    Ok(ongoing || counter < max_uses)
+}
+
+async fn consume_use(database_path: String, formated_addr: String, device_config: &config::Device, current_time: &chrono::DateTime<Tz>) -> error::Result<()> {
+    let max_uses = match device_config.max_uses {
+        Some(max_uses) => max_uses,
+        None => return Ok(()),
+    };
+
+    let (counter, ongoing) = get_used(database_path.clone(), formated_addr.clone(), device_config, current_time).await?;
+    if ongoing {
+        return Ok(());
+    }
 
-    let time_obj = chrono::NaiveTime::from_hms_opt(hours, minutes, seconds);
-    Ok(time_obj.unwrap())
+    debug!("{}: Using pass {} of {}", formated_addr.clone(), counter + 1, max_uses);
+    database::store_uses(database_path, formated_addr, counter + 1, current_time.timestamp() as u64, current_time.date_naive().to_string()).await
 }
 
-async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Config) -> error::Result<()> {
+async fn query_device(adapter: &Adapter, addr: Address, zone_name: &str, zone: &config::Zone, config: &mut config::Config, state: &mut state::State) -> error::Result<trigger::Decision> {
     let device = adapter.device(addr)
         .or(Err(error::new(format!("could not find device from addr: {}", addr))))?;
 
     let formated_addr = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", addr[0], addr[1], addr[2], addr[3], addr[4], addr[5]);
+    let entity = zone::get_entity(zone_name, zone, &formated_addr);
+    let mut decision = trigger::Decision::new(formated_addr.clone(), zone_name.to_string(), entity);
 
     // Check if we have MD
     let md_res = device.manufacturer_data().await;
     if let Err(_err) = md_res {
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("no manufacturer data"));
     }
 
     let md_opt = md_res.unwrap();
-    if let None = md_opt {
+    if md_opt.is_none() {
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("no manufacturer data"));
     }
 
     let md = md_opt.unwrap();
@@ -83,30 +118,36 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
     }
 
     // Check if we have a config for that device
-    let c = config.devices.get_mut(&formated_addr.clone());
-    if let None = c {
+    let c = config.devices.get(&formated_addr.clone());
+    if c.is_none() {
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone().clone(), formated_addr.clone().clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("unknown device"));
     }
 
     let device_config = c.unwrap();
+    decision.name = device_config.name.clone();
+    decision.known = true;
+
+    // Check if the device is granted inside this zone
+    if !zone::is_granted(zone_name, device_config) {
+        debug!("{} is not granted in zone {}", formated_addr.clone(), zone_name);
+        return Ok(decision.deny("not granted in zone"));
+    }
 
     // Check if inside RSSI cutoff, this can be used to limit range
     let rssi_res = device.rssi().await;
     if let Err(_err) = rssi_res {
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("no rssi"));
     }
 
     // We have a RSSI, check it
     let rssi_opt = rssi_res.unwrap();
+    decision.rssi = rssi_opt;
     if let Some(rssi) = rssi_opt {
-        if rssi < device_config.cutoff_rssi {
+        if rssi < device_config.cutoff_rssi || zone.cutoff_rssi.is_some_and(|cutoff_rssi| rssi < cutoff_rssi) {
             // Ensure that devices with no config are not triggered
-            trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone()).await?;
-            return Ok(());
+            return Ok(decision.deny("rssi below cutoff"));
         }
     }
 
@@ -116,8 +157,7 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
         warn!("{} has a device key configured which can't be decoded: {}", formated_addr.clone(), err);
         
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("invalid key"));
     }
 
     let device_key = decoded_key_res.unwrap();
@@ -127,18 +167,16 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
         warn!("{} has a device key configured which is not 16 bytes long", formated_addr.clone());
         
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("invalid key"));
     }
 
     // Check if we have the correct manufacture data
     let md_sel = md.get(&device_config.manufacture);
-    if let None = md_sel {
+    if md_sel.is_none() {
         warn!("{} presented wrong manufacture data key", formated_addr.clone());
         
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("wrong manufacturer data"));
     }
 
     let md_data = md_sel.unwrap();
@@ -148,8 +186,7 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
         warn!("{} presented invalid manufacture data length: {}", formated_addr.clone(), md_data.len());
         
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("wrong manufacturer data"));
     }
 
     // Init correct parameters for AES
@@ -171,7 +208,7 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
     
     // XOR with IV
     for n in 0..16 {
-        buf[n] = buf[n] ^ iv[n];
+        buf[n] ^= iv[n];
     }
 
     // Check for device id
@@ -181,14 +218,14 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
             .ok_or(error::new("device_id has no index".to_string()))?;
         if buf[i] != *di {
             warn!("{} presented invalid device ID", formated_addr.clone());
-            trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-            return Ok(());
+            return Ok(decision.deny("invalid device id"));
         }
     }
 
     // Check for restart counter
     let restart_counter_known = database::get_restarts(config.database_path.clone(), formated_addr.clone().clone()).await?;
     let restart_counter_device = byteorder::BE::read_u16(&buf[10..12]);
+    decision.restart_counter = Some(restart_counter_device);
 
     // We only check for not being equal
     if restart_counter_known != restart_counter_device {
@@ -199,15 +236,13 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
                 database::store_restarts(config.database_path.clone(), formated_addr.clone().clone(), restart_counter_device).await?;
             } else {
                 warn!("{} presented too high restart counter [overflow]", formated_addr.clone());
-                trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-                return Ok(());
+                return Ok(decision.deny("invalid restart counter"));
             }
         } else if restart_counter_known + 1 == restart_counter_device {
             database::store_restarts(config.database_path.clone(), formated_addr.clone().clone(), restart_counter_device).await?;
         } else {
             warn!("{} presented too high restart counter. Was {} should be {}", formated_addr.clone(), restart_counter_device, restart_counter_known);
-            trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-            return Ok(());
+            return Ok(decision.deny("invalid restart counter"));
         }
     }
 
@@ -235,97 +270,209 @@ async fn query_device(adapter: &Adapter, addr: Address, config: &mut config::Con
     }
 
     let skew = diff.abs_diff(diff_tag as u64);
+    decision.skew = Some(skew);
     if skew > config.allowed_skew as u64 {
         warn!("{} time was out of sync by {}", formated_addr.clone(), skew);
         
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone().clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("time out of sync"));
     }            
 
-    // We need to get the day
-    let current_time = chrono::Local::now().naive_local();
-    let day = match current_time.weekday() {
-        Weekday::Mon => "monday",
-        Weekday::Tue => "tuesday",
-        Weekday::Wed => "wednesday",
-        Weekday::Thu => "thursday",
-        Weekday::Fri => "friday",
-        Weekday::Sat => "saturday",
-        Weekday::Sun => "sunday",
-    };
+    // Check if the controller wide mode denies access
+    if let Some(mode) = mode::is_allowed(config, &formated_addr).await? {
+        info!("{} denied: {}", formated_addr.clone(), mode);
+        return Ok(decision.deny(&mode.to_string()));
+    }
+
+    // We need to get the day in the configured time zone
+    let time_zone = schedule::get_time_zone(device_config.time_zone.as_ref().or(zone.time_zone.as_ref()).or(config.time_zone.as_ref()))?;
+    let current_time = chrono::Utc::now().with_timezone(&time_zone);
+    let day = schedule::get_day(&current_time);
 
     // Check if we have a config for the day
     let times_option = device_config.allowed_times.get(day);
-    if let None = times_option {
+    if times_option.is_none() {
         info!("{} wanted to get access on a non configured day", formated_addr.clone());
 
         // Ensure that devices with no config are not triggered
-        trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-        return Ok(());
+        return Ok(decision.deny("non configured day"));
     }
 
     let times = times_option.unwrap();
-    let current_time_local = current_time.time();
+    if !schedule::in_times(times, &current_time, config.location.as_ref()).await? {
+        info!("{} has no access this time of the day", formated_addr.clone());
+        return Ok(decision.deny("outside schedule"));
+    }
 
-    for time in times {
-        let mut splits = time.split("-");
+    // Zones can restrict access further
+    if let Some(zone_times) = &zone.allowed_times {
+        let zone_time_zone = schedule::get_time_zone(zone.time_zone.as_ref().or(config.time_zone.as_ref()))?;
+        let zone_time = current_time.with_timezone(&zone_time_zone);
 
-        let start = splits.next();
-        let end = splits.next();
+        if !schedule::is_scheduled(zone_times, &zone_time, config.location.as_ref()).await? {
+            info!("{} has no access to zone {} this time of the day", formated_addr.clone(), zone_name);
+            return Ok(decision.deny("outside zone schedule"));
+        }
+    }
 
-        if let Some(start_str) = start {
-            if let Some(end_str) = end {
-                let start_time = get_time(start_str).await?;
-                let end_time = get_time(end_str).await?;
+    // Zones can require Home Assistant states, e.g. an alarm being disarmed
+    if !zone.conditions.is_empty() {
+        let unmet = match &state.home_assistant_states {
+            Some(states) => trigger::get_unmet_condition(&zone.conditions, states),
+            None => zone.conditions.keys().next().cloned(),
+        };
 
-                if current_time_local >= start_time && current_time_local <= end_time {
-                    info!("{} is allowed. Triggering", formated_addr.clone());
-                    trigger::trigger_on(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-                    return Ok(());
-                }
-            }
+        if let Some(entity_id) = unmet {
+            info!("{} denied: condition {} is not met in zone {}", formated_addr.clone(), entity_id, zone_name);
+            return Ok(decision.deny("condition not met"));
         }
     }
 
-    info!("{} has no access this time of the day", formated_addr.clone());
-    trigger::trigger_off(formated_addr.clone(), device_config.name.clone(), config.home_assistant.clone()).await?;
-    Ok(())
+    // Anti-passback denies entering again without leaving
+    let now = chrono::Utc::now().timestamp() as u64;
+    if presence::is_passback(config.database_path.clone(), zone, formated_addr.clone(), now).await? {
+        info!("{} denied: anti-passback in zone {}", formated_addr.clone(), zone_name);
+        return Ok(decision.deny("anti-passback"));
+    }
+
+    // Limited-use passes need to have a use left
+    if !has_use(config.database_path.clone(), formated_addr.clone(), device_config, &current_time).await? {
+        info!("{} denied: uses exhausted", formated_addr.clone());
+        return Ok(decision.deny("uses exhausted"));
+    }
+
+    // Sensitive zones need valid frames of multiple authorised persons
+    if let Some(min_persons) = zone.min_persons {
+        let window = time::Duration::from_secs(zone.min_persons_window.unwrap_or(zone::DEFAULT_MIN_PERSONS_WINDOW));
+        let persons = state.record_sighting(zone_name, &formated_addr, window);
+
+        if persons < min_persons {
+            info!("{} denied: waiting for more persons in zone {} ({} of {})", formated_addr.clone(), zone_name, persons, min_persons);
+            return Ok(decision.deny("waiting for more persons"));
+        }
+    }
+
+    consume_use(config.database_path.clone(), formated_addr.clone(), device_config, &current_time).await?;
+
+    presence::record(config.database_path.clone(), zone_name, zone, formated_addr.clone(), now).await?;
+
+    info!("{} is allowed. Triggering", formated_addr.clone());
+    Ok(decision.grant())
 }
 
-async fn start_ble(config: &mut config::Config) -> error::Result<()> {
+async fn start_ble(config: &mut config::Config, triggers: Arc<trigger::Triggers>) -> error::Result<()> {
     let session = bluer::Session::new().await?;
-    let adapter = session.adapter("hci0")?;
-    adapter.set_powered(true).await?;
+    let zones = zone::get_zones(config);
 
-    let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
-    let device_events = adapter.discover_devices_with_changes().await?;
-    pin_mut!(device_events);
+    // Every adapter is only discovered once, even if it serves multiple zones
+    let mut adapters: HashMap<String, Adapter> = HashMap::new();
+    let mut zone_adapters: Vec<(String, config::Zone, String)> = Vec::new();
+    let mut streams = Vec::new();
+
+    for (zone_name, zone) in zones {
+        let adapter = match &zone.adapter {
+            Some(adapter_name) => session.adapter(adapter_name)?,
+            None => session.default_adapter().await?,
+        };
+        let adapter_name = adapter.name().to_string();
+
+        info!("Zone {} uses adapter {}", zone_name, adapter_name);
+        zone_adapters.push((zone_name, zone, adapter_name.clone()));
+
+        if adapters.contains_key(&adapter_name) {
+            continue;
+        }
+
+        adapter.set_powered(true).await?;
+        let device_events = adapter.discover_devices_with_changes().await?;
+        let stream_adapter_name = adapter_name.clone();
+        streams.push(device_events.map(move |device_event| (stream_adapter_name.clone(), device_event)).boxed());
+        adapters.insert(adapter_name, adapter);
+    }
+
+    let mut device_events = futures::stream::select_all(streams);
+    let mut state = state::State::default();
+    state.home_assistant_states = triggers.home_assistant_states();
+
+    let mut timer = tokio::time::interval(time::Duration::from_millis(TIMER_INTERVAL));
 
     loop {
-        match device_events.next().await {
-            Some(device_event) => {
-                match device_event {
-                    AdapterEvent::DeviceAdded(addr) => {
-                        let res = query_device(&adapter, addr, config).await;
-                        if let Err(err) = res {
-                            error!("Error in discovery with {}: {}", addr, &err);
+        tokio::select! {
+            Some((adapter_name, device_event)) = device_events.next() => {
+                let adapter = adapters.get(&adapter_name)
+                    .ok_or(error::new(format!("event from unknown adapter {}", adapter_name)))?;
+
+                for (zone_name, zone, _) in zone_adapters.iter().filter(|(_, _, zone_adapter)| *zone_adapter == adapter_name) {
+                    let decision = match device_event {
+                        AdapterEvent::DeviceAdded(addr) => {
+                            let res = query_device(adapter, addr, zone_name, zone, config, &mut state).await;
+                            match res {
+                                Ok(decision) => decision,
+                                Err(err) => {
+                                    error!("Error in discovery with {} in zone {}: {}", addr, zone_name, &err);
+
+                                    let formated_addr = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", addr[0], addr[1], addr[2], addr[3], addr[4], addr[5]);
+                                    trigger::Decision::new(formated_addr.clone(), zone_name.clone(), zone::get_entity(zone_name, zone, &formated_addr))
+                                        .deny("error")
+                                }
+                            }
+                        }
+                        AdapterEvent::DeviceRemoved(addr) => {
+                            debug!("Device removed: {} in zone {}", addr, zone_name);
 
                             let formated_addr = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", addr[0], addr[1], addr[2], addr[3], addr[4], addr[5]);
-                            trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone().clone()).await?;
+                            let mut decision = trigger::Decision::new(formated_addr.clone(), zone_name.clone(), zone::get_entity(zone_name, zone, &formated_addr));
+                            if let Some(device_config) = config.devices.get(&formated_addr) {
+                                decision.name = device_config.name.clone();
+                                decision.known = true;
+                            }
+
+                            decision.release("removed")
                         }
+                        _ => continue,
+                    };
+
+                    let device_config = config.devices.get(&decision.device);
+                    let pulse = device_config.and_then(|device_config| device_config.pulse.as_ref())
+                        .or(zone.pulse.as_ref());
+
+                    if decision.outcome == trigger::Outcome::Granted {
+                        if let Some(pulse) = pulse {
+                            let duration = time::Duration::from_millis(pulse.duration_ms);
+                            let cooldown = time::Duration::from_secs(pulse.cooldown.unwrap_or(state::DEFAULT_PULSE_COOLDOWN));
+
+                            if !state.start_pulse(zone_name, &decision, duration, cooldown) {
+                                debug!("{} is cooling down in zone {}", decision.device, zone_name);
+                                continue;
+                            }
+                        } else if let Some(device_config) = device_config {
+                            let hold_time = time::Duration::from_secs(device_config.hold_time.unwrap_or(0));
+                            let absence_timeout = device_config.absence_timeout.or(config.absence_timeout).map(time::Duration::from_secs);
+                            state.hold(zone_name, &decision, hold_time, absence_timeout);
+                        }
+                    } else if state.is_pulsing(&decision.entity) || state.is_held(&decision.entity) {
+                        debug!("Holding {} in zone {}", decision.device, zone_name);
+                        continue;
+                    }
+
+                    triggers.publish(config, zone, &decision).await;
+                }
+            }
+            _ = timer.tick() => {
+                for (zone_name, decision) in state.take_pulse_ends() {
+                    if let Some((_, zone, _)) = zone_adapters.iter().find(|(name, _, _)| *name == zone_name) {
+                        triggers.publish(config, zone, &decision).await;
                     }
-                    AdapterEvent::DeviceRemoved(addr) => {
-                        debug!("Device removed: {}", addr);
+                }
 
-                        let formated_addr = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", addr[0], addr[1], addr[2], addr[3], addr[4], addr[5]);
-                        trigger::trigger_off(formated_addr.clone(), formated_addr.clone(), config.home_assistant.clone().clone()).await?;
+                for (zone_name, decision) in state.take_absent() {
+                    info!("{} left zone {}", decision.device, zone_name);
+
+                    if let Some((_, zone, _)) = zone_adapters.iter().find(|(name, _, _)| *name == zone_name) {
+                        triggers.publish(config, zone, &decision).await;
                     }
-                    _ => (),
                 }
             }
-            _ => ()
         }
     }
 }
@@ -345,6 +492,18 @@ struct Args {
     /// Set restart counter
     #[clap(short, long)]
     restart_counter: Option<u16>,
+
+    /// Reset the uses of a limited-use pass
+    #[clap(long)]
+    reset_uses: bool,
+
+    /// Mark a entity as inside or outside
+    #[clap(short, long, value_enum)]
+    presence: Option<presence::Presence>,
+
+    /// Switch the controller wide mode
+    #[clap(short, long, value_enum)]
+    mode: Option<mode::Mode>,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -357,20 +516,50 @@ async fn main() -> error::Result<()> {
     // Load configuration from disk
     let file_content = fs::read_to_string(args.config)?;
     let config_result: serde_yaml::Result<config::Config> = serde_yaml::from_str(&file_content);
-    if config_result.is_ok() {
-        let mut config = config_result.unwrap();
+    if let Ok(mut config) = config_result {
 
         // Start database
         database::init_database(&mut config).await?;
 
+        // Ensure all time zones can be resolved
+        schedule::validate(&config).await?;
+
+        // Ensure devices are only granted for known zones
+        zone::validate(&config).await?;
+
         // Check if we manipulate a state
-        if let Some(entity_id) = args.entity {
+        if let Some(new_mode) = args.mode {
+            mode::set_mode(config.database_path.clone(), new_mode).await?;
+        } else if let Some(entity_id) = args.entity {
             if let Some(restart_counter) = args.restart_counter {
                 database::store_restarts(config.database_path.clone(), entity_id.clone(), restart_counter).await?;
                 info!("Set \"{}\" restart counter to {}", entity_id.clone(), restart_counter);
             }
+
+            if args.reset_uses {
+                database::store_uses(config.database_path.clone(), entity_id.clone(), 0, 0, String::new()).await?;
+                info!("Reset \"{}\" uses", entity_id.clone());
+            }
+
+            if let Some(new_presence) = args.presence {
+                presence::set_presence(config.database_path.clone(), entity_id.clone(), new_presence).await?;
+            }
         } else {
-            start_ble(&mut config).await?;
+            // Ensure Home Assistant can be reached with the configured token
+            let mut clients = trigger::HttpClients::default();
+            home_assistant::validate(&mut config, &mut clients).await?;
+
+            let triggers = Arc::new(trigger::Triggers::new(&config, &mut clients)?);
+            let mode_client = match trigger::get_home_assistant(&config) {
+                Some(home_assistant) => Some(clients.get(&home_assistant.tls)?),
+                None => None,
+            };
+
+            control::start(config.clone()).await?;
+            tokio::spawn(mode::follow_home_assistant(config.clone(), triggers.home_assistant_states(), mode_client));
+            tokio::spawn(trigger::retry(triggers.clone()));
+
+            start_ble(&mut config, triggers).await?;
 
             loop {
                 tokio::time::sleep(time::Duration::from_secs(60)).await;
@@ -382,4 +571,71 @@ async fn main() -> error::Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn device(uses: &str) -> config::Device {
+        serde_yaml::from_str(&format!("key: \"\"\nname: tag\ndevice_id: \"\"\nmanufacture: 89\ncutoff_rssi: -99\nallowed_times: {{}}\n{}", uses)).unwrap()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> chrono::DateTime<Tz> {
+        Tz::UTC.with_ymd_and_hms(2024, 5, day, hour, minute, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn passes_run_out_after_max_uses() {
+        let database_path = database::tests::create("uses").await;
+        let device = device("max_uses: 2\nuses_cooldown: 60");
+        let addr = "00:00:00:00:00:01".to_string();
+
+        for time in [at(1, 8, 0), at(1, 9, 0)] {
+            assert!(has_use(database_path.clone(), addr.clone(), &device, &time).await.unwrap());
+            consume_use(database_path.clone(), addr.clone(), &device, &time).await.unwrap();
+        }
+
+        assert!(!has_use(database_path.clone(), addr.clone(), &device, &at(1, 10, 0)).await.unwrap());
+        // Totals do not start over the next day
+        assert!(!has_use(database_path, addr, &device, &at(2, 8, 0)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn frames_within_cooldown_are_one_use() {
+        let database_path = database::tests::create("uses-cooldown").await;
+        let device = device("max_uses: 1\nuses_cooldown: 60");
+        let addr = "00:00:00:00:00:01".to_string();
+
+        consume_use(database_path.clone(), addr.clone(), &device, &at(1, 8, 0)).await.unwrap();
+        consume_use(database_path.clone(), addr.clone(), &device, &at(1, 8, 0)).await.unwrap();
+
+        // Still the same use, even though the only one is taken
+        let within = Tz::UTC.with_ymd_and_hms(2024, 5, 1, 8, 0, 59).unwrap();
+        assert!(has_use(database_path.clone(), addr.clone(), &device, &within).await.unwrap());
+        assert!(!has_use(database_path, addr, &device, &at(1, 8, 1)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn checking_does_not_use_the_pass() {
+        let database_path = database::tests::create("uses-check").await;
+        let device = device("max_uses: 1");
+        let addr = "00:00:00:00:00:01".to_string();
+
+        for minute in 0..3 {
+            assert!(has_use(database_path.clone(), addr.clone(), &device, &at(1, 8, minute * 5)).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn daily_passes_start_over() {
+        let database_path = database::tests::create("uses-daily").await;
+        let device = device("max_uses: 1\nmax_uses_per_day: true");
+        let addr = "00:00:00:00:00:01".to_string();
+
+        consume_use(database_path.clone(), addr.clone(), &device, &at(1, 8, 0)).await.unwrap();
+        assert!(!has_use(database_path.clone(), addr.clone(), &device, &at(1, 18, 0)).await.unwrap());
+        assert!(has_use(database_path, addr, &device, &at(2, 8, 0)).await.unwrap());
+    }
+}
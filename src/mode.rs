@@ -0,0 +1,206 @@
+use std::{fmt::{Display, Formatter, self}, str::FromStr};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{error, config, database, trigger};
+
+/// Default seconds between polls of the mode entity in Home Assistant
+const DEFAULT_POLL_INTERVAL: u64 = 10;
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum Mode {
+    /// Devices are granted according to their config
+    Normal,
+    /// Nobody is granted
+    Lockdown,
+    /// Only devices on the vacation allow-list are granted
+    Vacation,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Normal => write!(f, "normal"),
+            Mode::Lockdown => write!(f, "lockdown"),
+            Mode::Vacation => write!(f, "vacation"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> error::Result<Mode> {
+        match s.trim().to_lowercase().as_str() {
+            "normal" => Ok(Mode::Normal),
+            "lockdown" => Ok(Mode::Lockdown),
+            "vacation" => Ok(Mode::Vacation),
+            _ => Err(error::new(format!("unknown mode: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EntityState {
+    state: String,
+}
+
+/// Checks if the current mode allows to grant the device
+pub(crate) async fn is_allowed(config: &config::Config, device: &String) -> error::Result<Option<Mode>> {
+    let mode = database::get_mode(config.database_path.clone()).await?;
+
+    match mode {
+        Mode::Normal => Ok(None),
+        Mode::Lockdown => Ok(Some(mode)),
+        Mode::Vacation => {
+            let allowed = config.modes.as_ref()
+                .map(|modes| modes.vacation_allow.contains(device))
+                .unwrap_or(false);
+
+            if allowed {
+                Ok(None)
+            } else {
+                Ok(Some(mode))
+            }
+        }
+    }
+}
+
+pub(crate) async fn set_mode(database_path: String, mode: Mode) -> error::Result<()> {
+    let current = database::get_mode(database_path.clone()).await?;
+    if current != mode {
+        database::store_mode(database_path, mode).await?;
+        info!("Mode changed from {} to {}", current, mode);
+    }
+
+    Ok(())
+}
+
+async fn get_entity_mode(entity: &str, home_assistant: &config::HomeAssistant, client: &reqwest::Client) -> error::Result<Mode> {
+    let url = format!("{}states/{}", home_assistant.url, urlencoding::encode(entity));
+    let entity_state: EntityState = client.get(url)
+        .bearer_auth(home_assistant.token.clone())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| error::new(format!("could not call home assistant: {:?}", e)))?
+        .json()
+        .await
+        .map_err(|e| error::new(format!("could not read state of {}: {:?}", entity, e)))?;
+
+    get_state_mode(&entity_state.state)
+}
+
+fn get_state_mode(state: &str) -> error::Result<Mode> {
+    // input_boolean entities switch the lockdown, input_select entities name the mode
+    match state {
+        "on" => Ok(Mode::Lockdown),
+        "off" => Ok(Mode::Normal),
+        state => state.parse::<Mode>(),
+    }
+}
+
+/// Takes over the mode of the entity only when its state changed, so a mode set
+/// locally stays until the entity changes
+async fn take_over(database_path: String, last: &mut Option<Mode>, mode: Mode) -> error::Result<()> {
+    if *last == Some(mode) {
+        return Ok(());
+    }
+
+    set_mode(database_path, mode).await?;
+    *last = Some(mode);
+
+    Ok(())
+}
+
+fn get_watched_mode(entity: &String, states: &trigger::HomeAssistantStates) -> Option<error::Result<Mode>> {
+    // The state is unknown while Home Assistant is not connected
+    let state = states.read().unwrap().get(entity).cloned()?;
+
+    Some(get_state_mode(&state))
+}
+
+/// Follows the configured Home Assistant entity and takes over its mode whenever it
+/// changes. The entity is taken from the WebSocket connection if there is one,
+/// otherwise it is polled.
+pub(crate) async fn follow_home_assistant(config: config::Config, states: Option<trigger::HomeAssistantStates>, client: Option<reqwest::Client>) {
+    let modes = match config.modes.clone() {
+        Some(modes) => modes,
+        None => return,
+    };
+
+    let entity = match modes.entity {
+        Some(entity) => entity,
+        None => return,
+    };
+
+    let home_assistant = trigger::get_home_assistant(&config).zip(client);
+    if home_assistant.is_none() && states.is_none() {
+        warn!("Mode entity {} is configured without a Home Assistant", entity);
+        return;
+    }
+
+    let interval = match states {
+        Some(_) => std::time::Duration::from_secs(1),
+        None => std::time::Duration::from_secs(modes.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL)),
+    };
+
+    let mut last = None;
+    loop {
+        let mode = match (&states, &home_assistant) {
+            (Some(states), _) => match get_watched_mode(&entity, states) {
+                Some(mode) => mode,
+                None => {
+                    tokio::time::sleep(interval).await;
+                    continue;
+                }
+            },
+            (None, Some((home_assistant, client))) => get_entity_mode(&entity, home_assistant, client).await,
+            (None, None) => return,
+        };
+
+        match mode {
+            Ok(mode) => {
+                debug!("{} reports mode {}", entity, mode);
+
+                if let Err(err) = take_over(config.database_path.clone(), &mut last, mode).await {
+                    warn!("Could not store mode from {}: {}", entity, err);
+                }
+            }
+            Err(err) => warn!("Could not get mode from {}: {}", entity, err),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_mode_survives_unchanged_entity() {
+        let database_path = database::tests::create("mode").await;
+        let mut last = None;
+
+        take_over(database_path.clone(), &mut last, Mode::Normal).await.unwrap();
+        set_mode(database_path.clone(), Mode::Lockdown).await.unwrap();
+
+        // The entity still reports normal
+        take_over(database_path.clone(), &mut last, Mode::Normal).await.unwrap();
+        assert_eq!(database::get_mode(database_path.clone()).await.unwrap(), Mode::Lockdown);
+
+        // Changing the entity takes over again
+        take_over(database_path.clone(), &mut last, Mode::Vacation).await.unwrap();
+        assert_eq!(database::get_mode(database_path).await.unwrap(), Mode::Vacation);
+    }
+
+    #[test]
+    fn states_are_mapped_to_modes() {
+        assert_eq!(get_state_mode("on").unwrap(), Mode::Lockdown);
+        assert_eq!(get_state_mode("off").unwrap(), Mode::Normal);
+        assert_eq!(get_state_mode("Vacation").unwrap(), Mode::Vacation);
+        assert!(get_state_mode("unavailable").is_err());
+    }
+}
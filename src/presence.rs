@@ -0,0 +1,95 @@
+//! Inside/outside tracking of tags and the anti-passback rule.
+//!
+//! A grant inside a zone with a direction marks the tag as inside (entry) or
+//! outside (exit). Entry zones with `anti_passback` deny a tag which is already
+//! marked inside, except for the grace period after it was marked, so a tag
+//! standing at the door keeps its grant.
+
+use std::fmt::{Display, Formatter, self};
+
+use log::info;
+
+use crate::{error, config, database};
+
+/// Default seconds after a passage in which the tag is not denied by anti-passback
+const DEFAULT_ANTI_PASSBACK_GRACE: u64 = 30;
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum Presence {
+    Inside,
+    Outside,
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Inside => write!(f, "inside"),
+            Presence::Outside => write!(f, "outside"),
+        }
+    }
+}
+
+impl Presence {
+    pub(crate) fn parse(s: &str) -> error::Result<Presence> {
+        match s.trim().to_lowercase().as_str() {
+            "inside" => Ok(Presence::Inside),
+            "outside" => Ok(Presence::Outside),
+            _ => Err(error::new(format!("unknown presence: {}", s))),
+        }
+    }
+}
+
+/// Checks if the anti-passback rule of the zone denies the device
+pub(crate) async fn is_passback(database_path: String, zone: &config::Zone, device: String, now: u64) -> error::Result<bool> {
+    if !zone.anti_passback || zone.direction != Some(config::Direction::Entry) {
+        return Ok(false);
+    }
+
+    let presence = database::get_presence(database_path, device).await?;
+    let grace = zone.anti_passback_grace.unwrap_or(DEFAULT_ANTI_PASSBACK_GRACE);
+
+    Ok(presence.inside && now.saturating_sub(presence.changed) > grace)
+}
+
+/// Records a grant of the device inside the zone
+pub(crate) async fn record(database_path: String, zone_name: &str, zone: &config::Zone, device: String, now: u64) -> error::Result<()> {
+    let presence = database::get_presence(database_path.clone(), device.clone()).await?;
+
+    let inside = match zone.direction {
+        Some(config::Direction::Entry) => true,
+        Some(config::Direction::Exit) => false,
+        None => presence.inside,
+    };
+
+    if inside == presence.inside && presence.zone == zone_name {
+        return Ok(());
+    }
+
+    if inside != presence.inside {
+        info!("{} is now {} (zone {})", device, if inside { Presence::Inside } else { Presence::Outside }, zone_name);
+    }
+
+    // Only a change of the inside state starts a new passage
+    let changed = if inside != presence.inside { now } else { presence.changed };
+    database::store_presence(database_path, device, inside, zone_name.to_string(), changed).await
+}
+
+pub(crate) async fn set_presence(database_path: String, device: String, presence: Presence) -> error::Result<()> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let current = database::get_presence(database_path.clone(), device.clone()).await?;
+
+    database::store_presence(database_path, device.clone(), presence == Presence::Inside, current.zone, now).await?;
+    info!("Set \"{}\" presence to {}", device, presence);
+
+    Ok(())
+}
+
+pub(crate) async fn get_presence(database_path: String, device: String) -> error::Result<Presence> {
+    let current = database::get_presence(database_path, device).await?;
+
+    if current.inside {
+        Ok(Presence::Inside)
+    } else {
+        Ok(Presence::Outside)
+    }
+}
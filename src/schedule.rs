@@ -0,0 +1,356 @@
+//! Evaluation of `allowed_times` schedules.
+//!
+//! Schedules are evaluated against the wall clock of an explicit IANA time zone
+//! instead of the TZ setting of the host. Every instant maps to exactly one wall
+//! clock time, so DST changes have the following semantics:
+//!
+//! * Skipped hour (clocks go forward): wall clock times inside the gap never occur.
+//!   A range only matches for the part of it that exists on that day.
+//! * Repeated hour (clocks go back): wall clock times inside the overlap occur twice.
+//!   A range matches on both passes.
+//!
+//! Besides fixed clock times (`08:00:00-18:00:00`) a range can be relative to
+//! sunrise and sunset at the configured location (`sunrise-30m..sunset+1h`).
+//! Both bounds of a `..` range can be a clock time or a sun event with an
+//! optional offset made of hours, minutes and seconds (`+1h30m`, `-45s`).
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, NaiveTime, Weekday};
+use chrono_tz::Tz;
+use log::{debug, warn};
+
+use crate::{error, config, sun};
+
+enum Bound {
+    Clock(NaiveTime),
+    Sun(sun::Event, chrono::Duration),
+}
+
+pub(crate) fn get_time_zone(name: Option<&String>) -> error::Result<Tz> {
+    match name {
+        Some(name) => name.parse::<Tz>()
+            .map_err(|err| error::new(format!("invalid time zone \"{}\": {}", name, err))),
+        None => get_host_time_zone(),
+    }
+}
+
+fn get_host_time_zone() -> error::Result<Tz> {
+    let name = iana_time_zone::get_timezone()
+        .map_err(|err| error::new(format!("could not detect host time zone, please configure time_zone: {:?}", err)))?;
+
+    name.parse::<Tz>()
+        .map_err(|err| error::new(format!("host time zone \"{}\" is unknown, please configure time_zone: {}", name, err)))
+}
+
+pub(crate) async fn validate(config: &config::Config) -> error::Result<()> {
+    if config.time_zone.is_none() {
+        warn!("No time_zone configured, using host time zone {}", get_host_time_zone()?);
+    }
+
+    get_time_zone(config.time_zone.as_ref())?;
+
+    for zone in config.zones.values() {
+        get_time_zone(zone.time_zone.as_ref().or(config.time_zone.as_ref()))?;
+
+        for times in zone.allowed_times.iter().flat_map(|allowed_times| allowed_times.values()) {
+            validate_times(times, config.location.as_ref()).await?;
+        }
+    }
+
+    for device_config in config.devices.values() {
+        get_time_zone(device_config.time_zone.as_ref().or(config.time_zone.as_ref()))?;
+
+        for times in device_config.allowed_times.values() {
+            validate_times(times, config.location.as_ref()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn get_day(time: &DateTime<Tz>) -> &'static str {
+    match time.weekday() {
+        Weekday::Mon => "monday",
+        Weekday::Tue => "tuesday",
+        Weekday::Wed => "wednesday",
+        Weekday::Thu => "thursday",
+        Weekday::Fri => "friday",
+        Weekday::Sat => "saturday",
+        Weekday::Sun => "sunday",
+    }
+}
+
+async fn get_time(str: &str) -> error::Result<chrono::NaiveTime> {
+    let mut splits = str.split(':');
+
+    let hours = splits.next()
+        .unwrap()
+        .parse::<u32>()
+        .or(Err(error::new("could not parse hours to int".to_string())))?;
+
+    let minutes = splits.next()
+        .ok_or(error::new("time has no minutes".to_string()))?
+        .parse::<u32>()
+        .or(Err(error::new("could not parse minutes to int".to_string())))?;
+
+    let seconds = splits.next()
+        .ok_or(error::new("time has no seconds".to_string()))?
+        .parse::<u32>()
+        .or(Err(error::new("could not parse seconds to int".to_string())))?;
+
+    chrono::NaiveTime::from_hms_opt(hours, minutes, seconds)
+        .ok_or(error::new(format!("invalid time: {}", str)))
+}
+
+fn get_offset(str: &str) -> error::Result<chrono::Duration> {
+    if str.is_empty() {
+        return Ok(chrono::Duration::zero());
+    }
+
+    let (sign, units) = match str.split_at(1) {
+        ("+", units) => (1, units),
+        ("-", units) => (-1, units),
+        _ => return Err(error::new(format!("offset has to start with + or -: {}", str))),
+    };
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in units.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let value = number.parse::<i64>()
+            .or(Err(error::new(format!("could not parse offset to int: {}", str))))?;
+        number.clear();
+
+        seconds += match c {
+            'h' => value * 3600,
+            'm' => value * 60,
+            's' => value,
+            _ => return Err(error::new(format!("unknown offset unit {} in {}", c, str))),
+        };
+    }
+
+    if !number.is_empty() || units.is_empty() {
+        return Err(error::new(format!("offset is missing a unit: {}", str)));
+    }
+
+    Ok(chrono::Duration::seconds(sign * seconds))
+}
+
+async fn get_bound(str: &str) -> error::Result<Bound> {
+    if let Some(offset) = str.strip_prefix("sunrise") {
+        return Ok(Bound::Sun(sun::Event::Sunrise, get_offset(offset)?));
+    }
+
+    if let Some(offset) = str.strip_prefix("sunset") {
+        return Ok(Bound::Sun(sun::Event::Sunset, get_offset(offset)?));
+    }
+
+    Ok(Bound::Clock(get_time(str).await?))
+}
+
+fn resolve_bound(bound: &Bound, time: &DateTime<Tz>, location: Option<&config::Location>) -> error::Result<Option<NaiveTime>> {
+    match bound {
+        Bound::Clock(clock) => Ok(Some(*clock)),
+        Bound::Sun(event, offset) => {
+            let location = location
+                .ok_or(error::new("sunrise and sunset ranges need a configured location".to_string()))?;
+
+            // The sun event is calculated for the local day and then shifted by the offset
+            let event_time = sun::get_event(*event, time.date_naive(), location.latitude, location.longitude);
+            Ok(event_time.map(|event_time| (event_time + *offset).with_timezone(&time.timezone()).time()))
+        }
+    }
+}
+
+async fn validate_times(times: &[String], location: Option<&config::Location>) -> error::Result<()> {
+    for time in times {
+        if let Some((start_str, end_str)) = time.split_once("..") {
+            for bound in [get_bound(start_str).await?, get_bound(end_str).await?] {
+                if let Bound::Sun(_, _) = bound {
+                    if location.is_none() {
+                        return Err(error::new(format!("range {} needs a configured location", time)));
+                    }
+                }
+            }
+        } else if let Some((start_str, end_str)) = time.split_once('-') {
+            get_time(start_str).await?;
+            get_time(end_str).await?;
+        } else {
+            return Err(error::new(format!("invalid time range: {}", time)));
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn in_times(times: &[String], time: &DateTime<Tz>, location: Option<&config::Location>) -> error::Result<bool> {
+    let current_time_local = time.time();
+
+    for time_range in times {
+        // Ranges which can also contain sun events
+        if let Some((start_str, end_str)) = time_range.split_once("..") {
+            let start_time = resolve_bound(&get_bound(start_str).await?, time, location)?;
+            let end_time = resolve_bound(&get_bound(end_str).await?, time, location)?;
+
+            if let (Some(start_time), Some(end_time)) = (start_time, end_time) {
+                if current_time_local >= start_time && current_time_local <= end_time {
+                    return Ok(true);
+                }
+            } else {
+                debug!("{} does not happen on {}", time_range, time.date_naive());
+            }
+
+            continue;
+        }
+
+        let mut splits = time_range.split('-');
+
+        let start = splits.next();
+        let end = splits.next();
+
+        if let Some(start_str) = start {
+            if let Some(end_str) = end {
+                let start_time = get_time(start_str).await?;
+                let end_time = get_time(end_str).await?;
+
+                if current_time_local >= start_time && current_time_local <= end_time {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Checks if the schedule has a range for the day matching the time
+pub(crate) async fn is_scheduled(allowed_times: &HashMap<String, Vec<String>>, time: &DateTime<Tz>, location: Option<&config::Location>) -> error::Result<bool> {
+    match allowed_times.get(get_day(time)) {
+        Some(times) => in_times(times, time, location).await,
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+
+    fn berlin(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Tz> {
+        let utc = NaiveDate::from_ymd_opt(year, month, day).unwrap()
+            .and_hms_opt(hour, minute, 0).unwrap();
+        Tz::Europe__Berlin.from_utc_datetime(&utc)
+    }
+
+    fn times(ranges: &[&str]) -> Vec<String> {
+        ranges.iter().map(|range| range.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn range_inside_skipped_hour_never_matches() {
+        let times = times(&["02:10:00-02:50:00"]);
+
+        // 2024-03-31 02:00 CET jumps to 03:00 CEST at 01:00 UTC
+        for minute in 0..180 {
+            let time = berlin(2024, 3, 31, minute / 60, minute % 60);
+            assert!(!in_times(&times, &time, None).await.unwrap(), "{} matched", time);
+        }
+    }
+
+    #[tokio::test]
+    async fn range_spanning_skipped_hour_matches_existing_part() {
+        let times = times(&["01:30:00-03:30:00"]);
+
+        assert!(in_times(&times, &berlin(2024, 3, 31, 0, 45), None).await.unwrap());
+        assert!(in_times(&times, &berlin(2024, 3, 31, 1, 15), None).await.unwrap());
+        assert!(!in_times(&times, &berlin(2024, 3, 31, 1, 45), None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn range_in_repeated_hour_matches_both_passes() {
+        let mut allowed_times = HashMap::new();
+        allowed_times.insert("sunday".to_string(), times(&["02:15:00-02:45:00"]));
+
+        // 2024-10-27 03:00 CEST goes back to 02:00 CET at 01:00 UTC
+        let first = berlin(2024, 10, 27, 0, 30);
+        let second = berlin(2024, 10, 27, 1, 30);
+        assert_eq!(first.time(), second.time());
+
+        assert!(is_scheduled(&allowed_times, &first, None).await.unwrap());
+        assert!(is_scheduled(&allowed_times, &second, None).await.unwrap());
+        assert!(!is_scheduled(&allowed_times, &berlin(2024, 10, 27, 1, 50), None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn range_spanning_repeated_hour_matches_on_both_passes() {
+        let mut allowed_times = HashMap::new();
+        allowed_times.insert("sunday".to_string(), times(&["01:30:00-02:30:00"]));
+
+        // 01:45 CEST, 02:15 CEST, 02:45 CEST, 02:15 CET, 02:45 CET
+        assert!(is_scheduled(&allowed_times, &berlin(2024, 10, 26, 23, 45), None).await.unwrap());
+        assert!(is_scheduled(&allowed_times, &berlin(2024, 10, 27, 0, 15), None).await.unwrap());
+        assert!(!is_scheduled(&allowed_times, &berlin(2024, 10, 27, 0, 45), None).await.unwrap());
+        assert!(is_scheduled(&allowed_times, &berlin(2024, 10, 27, 1, 15), None).await.unwrap());
+        assert!(!is_scheduled(&allowed_times, &berlin(2024, 10, 27, 1, 45), None).await.unwrap());
+    }
+
+    #[test]
+    fn offsets_are_parsed() {
+        assert_eq!(get_offset("").unwrap(), chrono::Duration::zero());
+        assert_eq!(get_offset("+1h30m").unwrap(), chrono::Duration::seconds(5400));
+        assert_eq!(get_offset("-45s").unwrap(), chrono::Duration::seconds(-45));
+        assert_eq!(get_offset("-1h2m3s").unwrap(), chrono::Duration::seconds(-3723));
+    }
+
+    #[test]
+    fn invalid_offsets_are_rejected() {
+        assert!(get_offset("+30").is_err());
+        assert!(get_offset("+").is_err());
+        assert!(get_offset("30m").is_err());
+        assert!(get_offset("+1d").is_err());
+        assert!(get_offset("+h").is_err());
+    }
+
+    #[tokio::test]
+    async fn sun_ranges_follow_the_location() {
+        let berlin_location = config::Location { latitude: 52.52, longitude: 13.405 };
+        let daylight = times(&["sunrise..sunset"]);
+        let early = times(&["sunrise-30m..sunset+1h"]);
+
+        // Sunrise is at 04:43 and sunset at 21:33 local time on 2024-06-21
+        let noon = berlin(2024, 6, 21, 10, 0);
+        let before_sunrise = berlin(2024, 6, 21, 2, 30);
+        let after_sunset = berlin(2024, 6, 21, 20, 0);
+
+        assert!(in_times(&daylight, &noon, Some(&berlin_location)).await.unwrap());
+        assert!(!in_times(&daylight, &before_sunrise, Some(&berlin_location)).await.unwrap());
+        assert!(!in_times(&daylight, &after_sunset, Some(&berlin_location)).await.unwrap());
+        assert!(in_times(&early, &before_sunrise, Some(&berlin_location)).await.unwrap());
+        assert!(in_times(&early, &after_sunset, Some(&berlin_location)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sun_ranges_never_match_without_sun_events() {
+        let tromso = config::Location { latitude: 69.6496, longitude: 18.956 };
+        let daylight = times(&["sunrise..sunset"]);
+
+        // Polar day and polar night
+        for month in [6, 12] {
+            let noon = berlin(2024, month, 21, 11, 0);
+            assert!(!in_times(&daylight, &noon, Some(&tromso)).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn sun_ranges_need_a_location() {
+        let daylight = times(&["sunrise..sunset"]);
+
+        assert!(in_times(&daylight, &berlin(2024, 6, 21, 10, 0), None).await.is_err());
+        assert!(validate_times(&daylight, None).await.is_err());
+    }
+}
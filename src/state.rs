@@ -0,0 +1,147 @@
+//! Runtime state shared between the decisions for all devices.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::trigger;
+
+/// Seconds after a pulse ended before the entity can pulse again
+pub(crate) const DEFAULT_PULSE_COOLDOWN: u64 = 10;
+
+struct Pulse {
+    zone_name: String,
+    decision: trigger::Decision,
+    off_at: Instant,
+    cooldown_until: Instant,
+    /// If the off was already sent
+    ended: bool,
+}
+
+struct Held {
+    zone_name: String,
+    decision: trigger::Decision,
+    last_valid: Instant,
+    hold_time: Duration,
+    /// Devices without one are only released by BlueZ or a later decision
+    absence_timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    /// Last valid frame per zone and device
+    sightings: HashMap<String, HashMap<String, Instant>>,
+    /// Granted entities which are not released yet
+    held: HashMap<String, Held>,
+    /// Pulsed entities which are on or cooling down
+    pulses: HashMap<String, Pulse>,
+    /// Watched Home Assistant states, if a WebSocket connection is configured
+    pub(crate) home_assistant_states: Option<trigger::HomeAssistantStates>,
+}
+
+impl State {
+    /// Records a valid frame of the device inside the zone and returns how many
+    /// distinct devices presented a valid frame there within the window
+    pub(crate) fn record_sighting(&mut self, zone_name: &str, device: &str, window: Duration) -> usize {
+        let now = Instant::now();
+        let sightings = self.sightings.entry(zone_name.to_string()).or_default();
+
+        sightings.insert(device.to_string(), now);
+        sightings.retain(|_, seen| now.duration_since(*seen) <= window);
+
+        sightings.len()
+    }
+
+    /// Keeps the entity of a grant on until its device is absent
+    pub(crate) fn hold(&mut self, zone_name: &str, decision: &trigger::Decision, hold_time: Duration, absence_timeout: Option<Duration>) {
+        self.held.insert(decision.entity.clone(), Held {
+            zone_name: zone_name.to_string(),
+            decision: decision.clone(),
+            last_valid: Instant::now(),
+            hold_time,
+            absence_timeout,
+        });
+    }
+
+    /// Checks if the entity has to stay on, otherwise it is not held anymore
+    pub(crate) fn is_held(&mut self, entity: &str) -> bool {
+        let held = match self.held.get(entity) {
+            Some(held) => held.last_valid.elapsed() < held.hold_time,
+            None => false,
+        };
+
+        if !held {
+            self.held.remove(entity);
+        }
+
+        held
+    }
+
+    /// Releases and returns the decisions of devices absent for longer than their timeout
+    pub(crate) fn take_absent(&mut self) -> Vec<(String, trigger::Decision)> {
+        let absent: Vec<String> = self.held.iter()
+            .filter(|(_, held)| held.absence_timeout.is_some_and(|absence_timeout| held.last_valid.elapsed() >= absence_timeout.max(held.hold_time)))
+            .map(|(entity, _)| entity.clone())
+            .collect();
+
+        absent.iter()
+            .filter_map(|entity| self.held.remove(entity))
+            .map(|held| (held.zone_name, held.decision.release("absent")))
+            .collect()
+    }
+
+    /// Starts a pulse for the entity of a grant, unless it is still cooling down
+    pub(crate) fn start_pulse(&mut self, zone_name: &str, decision: &trigger::Decision, duration: Duration, cooldown: Duration) -> bool {
+        let now = Instant::now();
+
+        if self.pulses.get(&decision.entity).is_some_and(|pulse| now < pulse.cooldown_until) {
+            return false;
+        }
+
+        self.pulses.insert(decision.entity.clone(), Pulse {
+            zone_name: zone_name.to_string(),
+            decision: decision.clone(),
+            off_at: now + duration,
+            cooldown_until: now + duration + cooldown,
+            ended: false,
+        });
+
+        true
+    }
+
+    pub(crate) fn is_pulsing(&self, entity: &str) -> bool {
+        self.pulses.get(entity).is_some_and(|pulse| !pulse.ended)
+    }
+
+    /// Ends the pulses which are due and returns their release decisions
+    pub(crate) fn take_pulse_ends(&mut self) -> Vec<(String, trigger::Decision)> {
+        let now = Instant::now();
+        self.pulses.retain(|_, pulse| !pulse.ended || now < pulse.cooldown_until);
+
+        self.pulses.values_mut()
+            .filter(|pulse| !pulse.ended && now >= pulse.off_at)
+            .map(|pulse| {
+                pulse.ended = true;
+                (pulse.zone_name.clone(), pulse.decision.clone().release("pulse"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_devices_with_absence_timeout_are_released() {
+        let mut state = State::default();
+        let decision = trigger::Decision::new("AA:BB:CC:DD:EE:FF".to_string(), "door".to_string(), "AA:BB:CC:DD:EE:FF".to_string()).grant();
+        let other = trigger::Decision::new("11:22:33:44:55:66".to_string(), "door".to_string(), "11:22:33:44:55:66".to_string()).grant();
+
+        state.hold("door", &decision, Duration::ZERO, None);
+        state.hold("door", &other, Duration::ZERO, Some(Duration::ZERO));
+
+        let absent = state.take_absent();
+        assert_eq!(absent.len(), 1);
+        assert_eq!(absent[0].1.entity, other.entity);
+        assert_eq!(absent[0].1.outcome, trigger::Outcome::Released);
+    }
+}
@@ -0,0 +1,93 @@
+//! Local sunrise and sunset calculation.
+//!
+//! Uses the sunrise equation with the NOAA approximations, which is accurate to
+//! about a minute for latitudes outside of the polar circles.
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JULIAN: f64 = 2440587.5;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Event {
+    Sunrise,
+    Sunset,
+}
+
+/// Calculates the time of sunrise or sunset for a date at the given position.
+/// Returns `None` if the sun does not rise or set on that day (polar day or night).
+pub(crate) fn get_event(event: Event, date: NaiveDate, latitude: f64, longitude: f64) -> Option<DateTime<Utc>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let days = (date - epoch).num_days() as f64;
+
+    // Mean solar noon and solar mean anomaly
+    let mean_noon = days - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_noon).rem_euclid(360.0).to_radians();
+
+    // Equation of the center and ecliptic longitude
+    let center = 1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+
+    // Solar transit and declination of the sun
+    let transit = J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397_f64.to_radians().sin()).asin();
+
+    // Hour angle, corrected for refraction and the solar disc
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let julian = match event {
+        Event::Sunrise => transit - hour_angle / 360.0,
+        Event::Sunset => transit + hour_angle / 360.0,
+    };
+
+    let timestamp = ((julian - UNIX_EPOCH_JULIAN) * 86400.0).round() as i64;
+    Utc.timestamp_opt(timestamp, 0).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(event: Event, date: (i32, u32, u32), latitude: f64, longitude: f64, expected: &str) {
+        let date = NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap();
+        let expected = DateTime::parse_from_rfc3339(expected).unwrap().with_timezone(&Utc);
+        let time = get_event(event, date, latitude, longitude).unwrap();
+
+        // NOAA publishes times rounded to the minute
+        assert!((time - expected).num_seconds().abs() <= 60, "{:?} on {} was {}, expected {}", event, date, time, expected);
+    }
+
+    #[test]
+    fn matches_noaa_times() {
+        // Berlin at the summer solstice
+        assert_near(Event::Sunrise, (2024, 6, 21), 52.52, 13.405, "2024-06-21T02:43:00Z");
+        assert_near(Event::Sunset, (2024, 6, 21), 52.52, 13.405, "2024-06-21T19:33:00Z");
+
+        // New York at the winter solstice
+        assert_near(Event::Sunrise, (2024, 12, 21), 40.7128, -74.006, "2024-12-21T12:16:00Z");
+        assert_near(Event::Sunset, (2024, 12, 21), 40.7128, -74.006, "2024-12-21T21:32:00Z");
+
+        // Sydney in its winter, sunrise is on the previous UTC day
+        assert_near(Event::Sunrise, (2024, 6, 21), -33.8688, 151.2093, "2024-06-20T21:00:00Z");
+        assert_near(Event::Sunset, (2024, 6, 21), -33.8688, 151.2093, "2024-06-21T06:54:00Z");
+    }
+
+    #[test]
+    fn polar_day_and_night_have_no_events() {
+        let (latitude, longitude) = (69.6496, 18.956);
+
+        for date in [NaiveDate::from_ymd_opt(2024, 6, 21).unwrap(), NaiveDate::from_ymd_opt(2024, 12, 21).unwrap()] {
+            assert_eq!(get_event(Event::Sunrise, date, latitude, longitude), None);
+            assert_eq!(get_event(Event::Sunset, date, latitude, longitude), None);
+        }
+
+        // Tromsø still has a sunrise in March
+        assert!(get_event(Event::Sunrise, NaiveDate::from_ymd_opt(2024, 3, 20).unwrap(), latitude, longitude).is_some());
+    }
+}
@@ -0,0 +1,165 @@
+//! Runs a local command for decisions.
+//!
+//! The decision fields are passed as `BLE_FENCER_*` environment variables. The
+//! command is executed directly without a shell, so tag controlled data can never
+//! be interpolated into a command line.
+//!
+//! Commands run detached from the scan loop, their exit status is only logged.
+//! Commands only run when the outcome of an entity changes, or when it is
+//! denied for another reason. Failed commands are never retried.
+
+use std::{collections::HashMap, process::Stdio, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::process::{Child, Command};
+
+use crate::{error, config};
+
+use super::{Decision, Outcome, TriggerBackend};
+
+/// Default seconds after which the command is killed
+const DEFAULT_TIMEOUT: u64 = 10;
+
+pub(crate) struct CommandTrigger {
+    config: config::Command,
+    /// Last outcome per entity, with the reason of denials
+    last: Mutex<HashMap<String, (Outcome, Option<String>)>>,
+}
+
+impl CommandTrigger {
+    pub(crate) fn new(config: config::Command) -> error::Result<CommandTrigger> {
+        if config.on_grant.is_none() && config.on_deny.is_none() && config.on_release.is_none() {
+            return Err(error::new("command trigger needs at least one of on_grant, on_deny or on_release".to_string()));
+        }
+
+        for command in [&config.on_grant, &config.on_deny, &config.on_release].into_iter().flatten() {
+            if command.is_empty() {
+                return Err(error::new("command trigger has an empty command".to_string()));
+            }
+        }
+
+        Ok(CommandTrigger {
+            config,
+            last: Mutex::default(),
+        })
+    }
+}
+
+fn get_environment(decision: &Decision) -> Vec<(&'static str, String)> {
+    let outcome = match decision.outcome {
+        Outcome::Granted => "granted",
+        Outcome::Denied => "denied",
+        Outcome::Released => "released",
+    };
+
+    vec![
+        ("BLE_FENCER_DEVICE", decision.device.clone()),
+        ("BLE_FENCER_NAME", decision.name.clone()),
+        ("BLE_FENCER_ZONE", decision.zone.clone()),
+        ("BLE_FENCER_ENTITY", decision.entity.clone()),
+        ("BLE_FENCER_OUTCOME", outcome.to_string()),
+        ("BLE_FENCER_REASON", decision.reason.clone().unwrap_or_default()),
+        ("BLE_FENCER_RSSI", decision.rssi.map(|rssi| rssi.to_string()).unwrap_or_default()),
+        ("BLE_FENCER_RESTART_COUNTER", decision.restart_counter.map(|counter| counter.to_string()).unwrap_or_default()),
+        ("BLE_FENCER_SKEW", decision.skew.map(|skew| skew.to_string()).unwrap_or_default()),
+    ]
+}
+
+async fn wait(mut child: Child, program: String, device: String, timeout: Duration) {
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => info!("{} for {} exited with {}", program, device, status),
+        Ok(Ok(status)) => warn!("{} for {} exited with {}", program, device, status),
+        Ok(Err(err)) => warn!("Could not wait for {}: {}", program, err),
+        Err(_) => {
+            warn!("{} for {} timed out after {:?}, killing it", program, device, timeout);
+            if let Err(err) = child.kill().await {
+                warn!("Could not kill {}: {}", program, err);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for CommandTrigger {
+    // Commands have side effects, running them late or twice is worse than not at all
+    fn retries(&self, _decision: &Decision) -> bool {
+        false
+    }
+
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        // Tags keep advertising, so the command only runs when the outcome changes
+        // or a device is denied for another reason
+        let state = (decision.outcome, if decision.outcome == Outcome::Denied { decision.reason.clone() } else { None });
+        if self.last.lock().unwrap().insert(decision.entity.clone(), state.clone()) == Some(state) {
+            return Ok(());
+        }
+
+        let command = match decision.outcome {
+            Outcome::Granted => &self.config.on_grant,
+            Outcome::Denied => &self.config.on_deny,
+            Outcome::Released => &self.config.on_release,
+        };
+
+        let command = match command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+
+        let child = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(get_environment(decision))
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| error::new(format!("could not run {}: {}", command[0], e)))?;
+
+        let timeout = Duration::from_secs(self.config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        tokio::spawn(wait(child, command[0].clone(), decision.device.clone(), timeout));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_only_run_for_changes() {
+        let path = std::env::temp_dir().join(format!("ble-fencer-command-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = vec!["sh".to_string(), "-c".to_string(), "echo \"$BLE_FENCER_OUTCOME $BLE_FENCER_REASON\" >> \"$0\"".to_string(), path.display().to_string()];
+        let trigger = CommandTrigger::new(config::Command {
+            on_grant: Some(log.clone()),
+            on_deny: Some(log.clone()),
+            on_release: Some(log),
+            timeout: None,
+        }).unwrap();
+
+        let mut decision = Decision::new("AA:BB:CC:DD:EE:FF".to_string(), "door".to_string(), "AA:BB:CC:DD:EE:FF".to_string());
+        decision.known = true;
+
+        for decision in [
+            decision.clone().grant(),
+            decision.clone().grant(),
+            decision.clone().release("absent"),
+            decision.clone().release("failback"),
+            decision.clone().deny("outside schedule"),
+            decision.clone().deny("outside schedule"),
+            decision.clone().deny("lockdown"),
+        ] {
+            trigger.publish(&decision).await.unwrap();
+            // Keep the order of the lines
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "granted \nreleased absent\ndenied outside schedule\ndenied lockdown\n");
+    }
+}
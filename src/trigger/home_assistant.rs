@@ -0,0 +1,247 @@
+//! Home Assistant REST backend.
+//!
+//! The last published state of every entity is cached, so Home Assistant is
+//! only called when the state of an entity changes. All cached states are
+//! written again periodically with their latest attributes, since Home
+//! Assistant forgets them on restart. Unknown devices never cause any traffic.
+//!
+//! Entity ids are rendered from a template, `binary_sensor.{{entity}}` by
+//! default, where `{{entity}}`, `{{device}}`, `{{name}}` and `{{zone}}` are
+//! replaced by the fields of the decision.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{error, config};
+
+use super::{Decision, Outcome, TriggerBackend, get_object_id};
+
+/// Seconds between writing all published states again
+const DEFAULT_RESYNC_INTERVAL: u64 = 300;
+
+pub(super) const DEFAULT_ENTITY_ID: &str = "binary_sensor.{{entity}}";
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Entity {
+    entity_id: String,
+    state: String,
+    attributes: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone)]
+struct Published {
+    entity_id: String,
+    /// Latest decision, also when the state did not change
+    decision: Decision,
+    last_seen: Option<DateTime<Local>>,
+    last_denial_reason: Option<String>,
+}
+
+type PublishedStates = Arc<Mutex<HashMap<String, Published>>>;
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct EntityConfig {
+    pub(super) entity_id: Option<String>,
+    pub(super) device_class: Option<String>,
+    pub(super) icon: Option<String>,
+}
+
+#[derive(Clone)]
+struct Publisher {
+    config: config::HomeAssistant,
+    client: reqwest::Client,
+    /// Entity settings per device
+    entities: HashMap<String, EntityConfig>,
+}
+
+/// Writes `binary_sensor` states through the Home Assistant REST API
+pub(crate) struct HomeAssistantTrigger {
+    publisher: Publisher,
+    /// Last published state per entity
+    published: PublishedStates,
+}
+
+fn get_state(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Granted => "on",
+        Outcome::Denied | Outcome::Released => "off",
+    }
+}
+
+/// Renders the entity id template of a decision
+pub(super) fn get_entity_id(template: &str, decision: &Decision) -> String {
+    let rendered = template.replace("{{entity}}", &decision.entity)
+        .replace("{{device}}", &decision.device)
+        .replace("{{name}}", &decision.name)
+        .replace("{{zone}}", &decision.zone);
+
+    match rendered.split_once('.') {
+        Some((domain, object_id)) => format!("{}.{}", get_object_id(domain), get_object_id(object_id)),
+        None => format!("binary_sensor.{}", get_object_id(&rendered)),
+    }
+}
+
+/// Entity settings of every configured device
+pub(super) fn get_entity_configs(devices: &HashMap<String, config::Device>) -> HashMap<String, EntityConfig> {
+    devices.iter()
+        .map(|(addr, device_config)| (addr.clone(), EntityConfig {
+            entity_id: device_config.entity_id.clone(),
+            device_class: device_config.device_class.clone(),
+            icon: device_config.icon.clone(),
+        }))
+        .collect()
+}
+
+impl Publisher {
+    fn get_entity_config(&self, device: &str) -> EntityConfig {
+        self.entities.get(device).cloned().unwrap_or_default()
+    }
+
+    async fn post_state(&self, published: &Published) -> error::Result<()> {
+        let decision = &published.decision;
+        let entity_config = self.get_entity_config(&decision.device);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("friendly_name".to_string(), json!(decision.name));
+        attributes.insert("zone".to_string(), json!(decision.zone));
+        attributes.insert("rssi".to_string(), json!(decision.rssi));
+        attributes.insert("restart_counter".to_string(), json!(decision.restart_counter));
+        attributes.insert("last_seen".to_string(), json!(published.last_seen.map(|last_seen| last_seen.to_rfc3339())));
+        attributes.insert("last_denial_reason".to_string(), json!(published.last_denial_reason));
+        if let Some(device_class) = entity_config.device_class {
+            attributes.insert("device_class".to_string(), json!(device_class));
+        }
+        if let Some(icon) = entity_config.icon {
+            attributes.insert("icon".to_string(), json!(icon));
+        }
+
+        let entity = Entity {
+            entity_id: published.entity_id.clone(),
+            state: get_state(decision.outcome).to_string(),
+            attributes,
+        };
+
+        let url = format!("{}states/{}", self.config.url, urlencoding::encode(&published.entity_id));
+        debug!("Calling URL: {}", url);
+
+        self.client.post(url)
+            .bearer_auth(self.config.token.clone())
+            .json(&entity)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not call home assistant: {:?}", e)))?;
+
+        Ok(())
+    }
+
+    async fn fire_event(&self, event_type: &str, decision: &Decision) -> error::Result<()> {
+        let url = format!("{}events/{}", self.config.url, urlencoding::encode(event_type));
+        debug!("Calling URL: {}", url);
+
+        self.client.post(url)
+            .bearer_auth(self.config.token.clone())
+            .json(decision)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not fire home assistant event: {:?}", e)))?;
+
+        Ok(())
+    }
+}
+
+async fn resync(publisher: Publisher, published: PublishedStates) {
+    let interval = Duration::from_secs(publisher.config.resync_interval.unwrap_or(DEFAULT_RESYNC_INTERVAL));
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let entities: Vec<Published> = published.lock().unwrap().values().cloned().collect();
+        debug!("Resyncing {} Home Assistant states", entities.len());
+
+        for entity in entities {
+            if let Err(err) = publisher.post_state(&entity).await {
+                warn!("Could not resync {}: {}", entity.entity_id, err);
+                break;
+            }
+        }
+    }
+}
+
+impl HomeAssistantTrigger {
+    pub(crate) fn new(config: config::HomeAssistant, client: reqwest::Client, devices: &HashMap<String, config::Device>) -> HomeAssistantTrigger {
+        let publisher = Publisher {
+            config,
+            client,
+            entities: get_entity_configs(devices),
+        };
+        let published = PublishedStates::default();
+
+        tokio::spawn(resync(publisher.clone(), published.clone()));
+
+        HomeAssistantTrigger {
+            publisher,
+            published,
+        }
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for HomeAssistantTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        let template = self.publisher.get_entity_config(&decision.device).entity_id
+            .or(self.publisher.config.entity_id.clone())
+            .unwrap_or(DEFAULT_ENTITY_ID.to_string());
+        let last = self.published.lock().unwrap().get(&decision.entity).cloned();
+
+        let mut published = Published {
+            entity_id: get_entity_id(&template, decision),
+            decision: decision.clone(),
+            last_seen: last.as_ref().and_then(|last| last.last_seen),
+            last_denial_reason: last.as_ref().and_then(|last| last.last_denial_reason.clone()),
+        };
+        // Releases are not caused by a frame of the device
+        if decision.outcome != Outcome::Released {
+            published.last_seen = Some(Local::now());
+        }
+        if decision.outcome == Outcome::Denied {
+            published.last_denial_reason = decision.reason.clone();
+        }
+
+        match &last {
+            Some(last) if get_state(last.decision.outcome) == get_state(decision.outcome) => (),
+            _ => {
+                debug!("Triggering {} for {}", get_state(decision.outcome), published.entity_id);
+                self.publisher.post_state(&published).await?;
+            }
+        }
+
+        // Events carry why a device was granted or denied, so they follow reason changes too.
+        // A failed state write returns before, so its retries do not fire the event again.
+        let changed = last.is_none_or(|last| last.decision.outcome != decision.outcome || last.decision.reason != decision.reason);
+        if let Some(event_type) = &self.publisher.config.event {
+            if changed {
+                self.publisher.fire_event(event_type, decision).await?;
+            }
+        }
+
+        self.published.lock().unwrap().insert(decision.entity.clone(), published);
+
+        Ok(())
+    }
+
+    // The entity mirrors the latest state, a late grant is still the truth
+    fn retries(&self, _decision: &Decision) -> bool {
+        true
+    }
+}
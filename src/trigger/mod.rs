@@ -0,0 +1,524 @@
+//! Trigger backends which receive every access decision.
+//!
+//! Every decision is fanned out to all selected backends. A backend failing
+//! does not affect the others, its delivery is queued in the outbox instead.
+//! Only backends mirroring states queue grants.
+//!
+//! Backends in the `failover` chain of a zone are not fanned out to. The first
+//! healthy one which accepts the decision gets it, so e.g. a local relay opens
+//! the door while Home Assistant is down. Every failover is recorded. Once the
+//! primary backend accepts decisions again, the fallback is released. Grants
+//! delivered through the chain are never queued.
+
+mod home_assistant;
+mod mqtt;
+mod websocket;
+mod service;
+mod webhook;
+mod command;
+mod shelly;
+mod tasmota;
+mod modbus;
+mod osdp;
+mod outbox;
+
+use std::{collections::{HashMap, HashSet}, fs, future::Future, hash::Hash, sync::{Arc, Mutex, RwLock}, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{error, config, database};
+
+pub(crate) use outbox::retry;
+pub(crate) use websocket::get_unmet_condition;
+
+/// States of watched Home Assistant entities
+pub(crate) type HomeAssistantStates = Arc<RwLock<HashMap<String, String>>>;
+
+/// Name of the backend created from the top level `home_assistant` config
+pub(crate) const DEFAULT_TRIGGER: &str = "home_assistant";
+
+/// Seconds after which HTTP requests of backends time out
+const HTTP_TIMEOUT: u64 = 5;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Granted,
+    Denied,
+    Released,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub(crate) struct Decision {
+    pub(crate) device: String,
+    pub(crate) name: String,
+    pub(crate) zone: String,
+    pub(crate) entity: String,
+    /// If the device is configured
+    pub(crate) known: bool,
+    pub(crate) outcome: Outcome,
+    pub(crate) reason: Option<String>,
+    pub(crate) rssi: Option<i16>,
+    pub(crate) restart_counter: Option<u16>,
+    /// Seconds the tag time was off compared to the local time
+    pub(crate) skew: Option<u64>,
+}
+
+impl Decision {
+    pub(crate) fn new(device: String, zone: String, entity: String) -> Decision {
+        Decision {
+            name: device.clone(),
+            device,
+            zone,
+            entity,
+            known: false,
+            outcome: Outcome::Denied,
+            reason: None,
+            rssi: None,
+            restart_counter: None,
+            skew: None,
+        }
+    }
+
+    pub(crate) fn deny(mut self, reason: &str) -> Decision {
+        self.outcome = Outcome::Denied;
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub(crate) fn grant(mut self) -> Decision {
+        self.outcome = Outcome::Granted;
+        self.reason = None;
+        self
+    }
+
+    pub(crate) fn release(mut self, reason: &str) -> Decision {
+        self.outcome = Outcome::Released;
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+#[async_trait]
+pub(crate) trait TriggerBackend: Send + Sync {
+    async fn publish(&self, decision: &Decision) -> error::Result<()>;
+
+    /// Checks if a failed delivery of the decision may be retried later. Grants
+    /// are not, since opening a door minutes late is worse than not opening it.
+    fn retries(&self, decision: &Decision) -> bool {
+        decision.outcome != Outcome::Granted
+    }
+}
+
+struct Backend {
+    name: String,
+    backend: Box<dyn TriggerBackend>,
+    breaker: Mutex<outbox::CircuitBreaker>,
+}
+
+impl Backend {
+    fn is_queued(&self, decision: &Decision) -> bool {
+        // Only known devices are worth retrying, everything else would flood the outbox
+        decision.known && self.backend.retries(decision)
+    }
+}
+
+pub(crate) struct Triggers {
+    backends: Vec<Backend>,
+    home_assistant_states: Option<HomeAssistantStates>,
+    database_path: String,
+    /// Fallback backend and last outcome it delivered per entity
+    failed_over: Mutex<HashMap<String, (String, Outcome)>>,
+}
+
+/// Reads all certificates of a PEM bundle
+pub(crate) fn read_certificates(path: &str) -> error::Result<Vec<Vec<u8>>> {
+    let bundle = fs::read_to_string(path)
+        .map_err(|e| error::new(format!("could not read CA bundle {}: {}", path, e)))?;
+
+    let certificates: Vec<Vec<u8>> = bundle.split_inclusive("-----END CERTIFICATE-----")
+        .filter(|pem| pem.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|pem| pem.trim().as_bytes().to_vec())
+        .collect();
+
+    if certificates.is_empty() {
+        return Err(error::new(format!("CA bundle {} contains no certificates", path)));
+    }
+
+    Ok(certificates)
+}
+
+/// Reads the PEM client certificate and PKCS#8 key
+pub(crate) fn read_identity(tls: &config::Tls) -> error::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let cert = fs::read(client_cert)
+                .map_err(|e| error::new(format!("could not read client certificate {}: {}", client_cert, e)))?;
+            let key = fs::read(client_key)
+                .map_err(|e| error::new(format!("could not read client key {}: {}", client_key, e)))?;
+
+            Ok(Some((cert, key)))
+        }
+        (None, None) => Ok(None),
+        _ => Err(error::new("client_cert and client_key have to be configured together".to_string())),
+    }
+}
+
+/// HTTP client for backends, which does not block the scan loop for long
+pub(crate) fn get_http_client(tls: Option<&config::Tls>) -> error::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT));
+
+    if let Some(tls) = tls {
+        if let Some(ca_file) = &tls.ca_file {
+            for pem in read_certificates(ca_file)? {
+                let certificate = reqwest::Certificate::from_pem(&pem)
+                    .map_err(|e| error::new(format!("invalid certificate in {}: {}", ca_file, e)))?;
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some((cert, key)) = read_identity(tls)? {
+            let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                .map_err(|e| error::new(format!("invalid client certificate or key: {}", e)))?;
+            builder = builder.identity(identity);
+        }
+
+        if tls.insecure {
+            warn!("TLS certificates are not verified, only use this for testing");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    builder.build()
+        .map_err(|e| error::new(format!("could not create HTTP client: {}", e)))
+}
+
+/// HTTP clients shared by everything calling out with the same TLS settings
+#[derive(Default)]
+pub(crate) struct HttpClients {
+    clients: Vec<(Option<config::Tls>, reqwest::Client)>,
+}
+
+impl HttpClients {
+    pub(crate) fn get(&mut self, tls: &Option<config::Tls>) -> error::Result<reqwest::Client> {
+        if let Some((_, client)) = self.clients.iter().find(|(client_tls, _)| client_tls == tls) {
+            return Ok(client.clone());
+        }
+
+        let client = get_http_client(tls.as_ref())?;
+        self.clients.push((tls.clone(), client.clone()));
+
+        Ok(client)
+    }
+}
+
+/// Granted entities per output shared by several devices, e.g. the relay of a
+/// door. The output is switched on by the first grant and off once the last
+/// granted entity is released, denials leave it alone.
+pub(crate) struct Holders<K> {
+    holders: Mutex<HashMap<K, HashSet<String>>>,
+}
+
+impl<K> Default for Holders<K> {
+    fn default() -> Holders<K> {
+        Holders {
+            holders: Mutex::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Holders<K> {
+    /// Calls `switch` if the decision changes whether the output is held
+    pub(crate) async fn switch<F, R>(&self, output: K, decision: &Decision, switch: F) -> error::Result<()>
+    where
+        F: FnOnce(bool) -> R,
+        R: Future<Output = error::Result<()>>,
+    {
+        let on = match decision.outcome {
+            Outcome::Granted => true,
+            Outcome::Released => false,
+            Outcome::Denied => return Ok(()),
+        };
+
+        {
+            let mut holders = self.holders.lock().unwrap();
+            let entities = holders.entry(output.clone()).or_default();
+
+            if on && !entities.is_empty() {
+                entities.insert(decision.entity.clone());
+                return Ok(());
+            }
+
+            if !on && (!entities.contains(&decision.entity) || entities.len() > 1) {
+                entities.remove(&decision.entity);
+                return Ok(());
+            }
+        }
+
+        // The holders only change once the output was switched, so failures are repeated
+        switch(on).await?;
+
+        let mut holders = self.holders.lock().unwrap();
+        let entities = holders.entry(output).or_default();
+        if on {
+            entities.insert(decision.entity.clone());
+        } else {
+            entities.remove(&decision.entity);
+        }
+
+        Ok(())
+    }
+}
+
+/// Object id of an entity as Home Assistant accepts it
+pub(crate) fn get_object_id(entity: &str) -> String {
+    entity.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// Home Assistant instance used for reading states, e.g. the mode entity
+pub(crate) fn get_home_assistant(config: &config::Config) -> Option<config::HomeAssistant> {
+    if let Some(home_assistant) = &config.home_assistant {
+        return Some(home_assistant.clone());
+    }
+
+    config.triggers.iter().find_map(|trigger| match &trigger.kind {
+        config::TriggerKind::HomeAssistant(home_assistant) => Some(home_assistant.clone()),
+        config::TriggerKind::HomeAssistantService(service) => Some(config::HomeAssistant {
+            url: service.url.clone(),
+            token: service.token.clone(),
+            token_file: None,
+            event: None,
+            resync_interval: None,
+            entity_id: None,
+            tls: service.tls.clone(),
+        }),
+        _ => None,
+    })
+}
+
+/// Entities whose states are needed for decisions
+fn get_watched(config: &config::Config) -> HashSet<String> {
+    let mut watched: HashSet<String> = config.zones.values()
+        .flat_map(|zone| zone.conditions.keys().cloned())
+        .collect();
+
+    if let Some(entity) = config.modes.as_ref().and_then(|modes| modes.entity.clone()) {
+        watched.insert(entity);
+    }
+
+    watched
+}
+
+fn create_backend(kind: &config::TriggerKind, config: &config::Config, home_assistant_states: &mut Option<HomeAssistantStates>, clients: &mut HttpClients) -> error::Result<Box<dyn TriggerBackend>> {
+    match kind {
+        config::TriggerKind::HomeAssistant(home_assistant) => Ok(Box::new(home_assistant::HomeAssistantTrigger::new(home_assistant.clone(), clients.get(&home_assistant.tls)?, &config.devices))),
+        config::TriggerKind::Mqtt(mqtt) => Ok(Box::new(mqtt::MqttTrigger::new(mqtt.clone(), &config.devices))),
+        config::TriggerKind::HomeAssistantWebsocket(websocket) => {
+            // Only the first connection watches states
+            let states = HomeAssistantStates::default();
+            let watched = match home_assistant_states {
+                Some(_) => HashSet::new(),
+                None => get_watched(config),
+            };
+            home_assistant_states.get_or_insert(states.clone());
+
+            // Events refer to the entities of the REST backend unless configured otherwise
+            let mut websocket = websocket.clone();
+            if websocket.entity_id.is_none() {
+                websocket.entity_id = get_home_assistant(config).and_then(|home_assistant| home_assistant.entity_id);
+            }
+
+            Ok(Box::new(websocket::WebsocketTrigger::new(websocket, states, watched, &config.devices)?))
+        }
+        config::TriggerKind::HomeAssistantService(service) => Ok(Box::new(service::ServiceTrigger::new(service.clone(), clients.get(&service.tls)?, &config.devices))),
+        config::TriggerKind::Webhook(webhook) => Ok(Box::new(webhook::WebhookTrigger::new(webhook.clone(), clients.get(&webhook.tls)?)?)),
+        config::TriggerKind::Command(command) => Ok(Box::new(command::CommandTrigger::new(command.clone())?)),
+        config::TriggerKind::Shelly(shelly) => Ok(Box::new(shelly::ShellyTrigger::new(shelly.clone(), clients.get(&shelly.tls)?)?)),
+        config::TriggerKind::Tasmota(tasmota) => Ok(Box::new(tasmota::TasmotaTrigger::new(tasmota.clone(), clients.get(&tasmota.tls)?))),
+        config::TriggerKind::Modbus(modbus) => Ok(Box::new(modbus::ModbusTrigger::new(modbus.clone())?)),
+        config::TriggerKind::Osdp(osdp) => Ok(Box::new(osdp::OsdpTrigger::new(osdp.clone(), &config.devices)?)),
+    }
+}
+
+impl Triggers {
+    pub(crate) fn new(config: &config::Config, clients: &mut HttpClients) -> error::Result<Triggers> {
+        let mut backends: Vec<Backend> = Vec::new();
+        let mut home_assistant_states = None;
+
+        if let Some(home_assistant) = &config.home_assistant {
+            backends.push(Backend {
+                name: DEFAULT_TRIGGER.to_string(),
+                backend: create_backend(&config::TriggerKind::HomeAssistant(home_assistant.clone()), config, &mut home_assistant_states, clients)?,
+                breaker: Mutex::default(),
+            });
+        }
+
+        for trigger in config.triggers.iter() {
+            if backends.iter().any(|backend| backend.name == trigger.name) {
+                return Err(error::new(format!("trigger {} is configured more than once", trigger.name)));
+            }
+
+            backends.push(Backend {
+                name: trigger.name.clone(),
+                backend: create_backend(&trigger.kind, config, &mut home_assistant_states, clients)?,
+                breaker: Mutex::default(),
+            });
+        }
+
+        if home_assistant_states.is_none() && config.zones.values().any(|zone| !zone.conditions.is_empty()) {
+            return Err(error::new("zone conditions need a home_assistant_websocket trigger".to_string()));
+        }
+
+        // Selections can only reference configured backends
+        let selections = config.devices.values().filter_map(|device_config| device_config.triggers.as_ref())
+            .chain(config.zones.values().filter_map(|zone| zone.triggers.as_ref()))
+            .chain(config.zones.values().filter_map(|zone| zone.failover.as_ref()));
+        for selection in selections {
+            for name in selection {
+                if !backends.iter().any(|backend| backend.name == *name) {
+                    return Err(error::new(format!("unknown trigger {}", name)));
+                }
+            }
+        }
+
+        Ok(Triggers {
+            backends,
+            home_assistant_states,
+            database_path: config.database_path.clone(),
+            failed_over: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn home_assistant_states(&self) -> Option<HomeAssistantStates> {
+        self.home_assistant_states.clone()
+    }
+
+    /// Publishes the decision to all backends selected by the device, or else the zone
+    pub(crate) async fn publish(&self, config: &config::Config, zone: &config::Zone, decision: &Decision) {
+        let selection = config.devices.get(&decision.device)
+            .and_then(|device_config| device_config.triggers.as_ref())
+            .or(zone.triggers.as_ref());
+        let chain = zone.failover.as_ref();
+
+        let publishes = self.backends.iter()
+            .filter(|backend| selection.is_none_or(|selection| selection.contains(&backend.name)))
+            .filter(|backend| chain.is_none_or(|chain| !chain.contains(&backend.name)))
+            .map(|backend| async move {
+                if let Err(err) = self.deliver(backend, decision, true).await {
+                    warn!("Trigger {} failed for {}: {}", backend.name, decision.entity, err);
+                }
+            });
+
+        let fail_over = async {
+            if let Some(chain) = chain {
+                if let Err(err) = self.fail_over(chain, decision).await {
+                    warn!("Could not record failover for {}: {}", decision.entity, err);
+                }
+            }
+        };
+
+        futures::future::join(futures::future::join_all(publishes), fail_over).await;
+    }
+
+    /// Delivers the decision to the first backend of the chain which accepts it
+    async fn fail_over(&self, chain: &[String], decision: &Decision) -> error::Result<()> {
+        // A queued grant would be replayed after another backend of the chain already delivered it
+        let queue = decision.outcome != Outcome::Granted;
+
+        for (index, name) in chain.iter().enumerate() {
+            let backend = match self.backends.iter().find(|backend| backend.name == *name) {
+                Some(backend) => backend,
+                None => continue,
+            };
+
+            match self.deliver(backend, decision, queue).await {
+                Ok(true) => {
+                    if index == 0 {
+                        // The fallback must not keep e.g. a relay switched on
+                        let failed_over = self.failed_over.lock().unwrap().remove(&decision.entity);
+                        if let Some(fallback) = failed_over.and_then(|(fallback, _)| self.backends.iter().find(|backend| backend.name == fallback)) {
+                            if let Err(err) = self.deliver(fallback, &decision.clone().release("failback"), false).await {
+                                warn!("Trigger {} failed for {}: {}", fallback.name, decision.entity, err);
+                            }
+                        }
+                    } else {
+                        self.record_failover(&chain[0], &backend.name, decision).await?;
+                    }
+
+                    return Ok(());
+                }
+                Ok(false) => debug!("Trigger {} is unhealthy, failing over for {}", backend.name, decision.entity),
+                Err(err) => warn!("Trigger {} failed for {}, failing over: {}", backend.name, decision.entity, err),
+            }
+        }
+
+        warn!("No trigger of the failover chain accepted {:?} for {}", decision.outcome, decision.entity);
+        Ok(())
+    }
+
+    async fn record_failover(&self, primary_backend: &str, backend: &str, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        // Repeated frames of the same state are only recorded once
+        let last = self.failed_over.lock().unwrap().insert(decision.entity.clone(), (backend.to_string(), decision.outcome));
+        if last == Some((backend.to_string(), decision.outcome)) {
+            return Ok(());
+        }
+
+        warn!("Trigger {} delivered {:?} for {} instead of {}", backend, decision.outcome, decision.entity, primary_backend);
+
+        database::store_failover(self.database_path.clone(), database::FailoverDTO {
+            time: chrono::Utc::now().timestamp() as u64,
+            zone: decision.zone.clone(),
+            device: decision.device.clone(),
+            outcome: format!("{:?}", decision.outcome).to_lowercase(),
+            primary_backend: primary_backend.to_string(),
+            backend: backend.to_string(),
+        }).await
+    }
+
+    /// Delivers the decision or queues it if allowed, returns false if the backend is skipped as unhealthy
+    async fn deliver(&self, backend: &Backend, decision: &Decision, queue: bool) -> error::Result<bool> {
+        if !backend.breaker.lock().unwrap().is_closed() {
+            debug!("Trigger {} is failing, skipping {:?} for {}", backend.name, decision.outcome, decision.entity);
+
+            if queue && backend.is_queued(decision) {
+                outbox::enqueue(self.database_path.clone(), &backend.name, decision).await?;
+            }
+            return Ok(false);
+        }
+
+        debug!("Publishing {:?} for {} to {}", decision.outcome, decision.entity, backend.name);
+
+        match backend.backend.publish(decision).await {
+            Ok(()) => {
+                backend.breaker.lock().unwrap().success();
+
+                // A queued older state must not overwrite this one
+                if decision.known {
+                    database::delete_outbox(self.database_path.clone(), backend.name.clone(), decision.entity.clone()).await?;
+                }
+
+                Ok(true)
+            }
+            Err(err) => {
+                if backend.breaker.lock().unwrap().failure() {
+                    warn!("Trigger {} keeps failing, pausing direct deliveries", backend.name);
+                }
+
+                if queue && backend.is_queued(decision) {
+                    outbox::enqueue(self.database_path.clone(), &backend.name, decision).await?;
+                }
+
+                Err(err)
+            }
+        }
+    }
+}
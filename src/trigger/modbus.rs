@@ -0,0 +1,281 @@
+//! Modbus TCP coils, e.g. of industrial relay modules.
+//!
+//! The first grant sets the coil and it is cleared once the last device granted
+//! on it is released, denials are ignored. The coil is looked up by device
+//! address, then by zone name, before falling back to the default `coil`. Connections are kept open and reused, if a reused connection
+//! turns out to be broken the write is repeated on a new one.
+
+use std::{collections::HashMap, sync::atomic::{AtomicU16, Ordering}, time::Duration};
+
+use async_trait::async_trait;
+use byteorder::{BE, ByteOrder};
+use log::debug;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::Mutex};
+
+use crate::{error, config};
+
+use super::{Decision, Holders, TriggerBackend};
+
+const DEFAULT_PORT: u16 = 502;
+const DEFAULT_UNIT_ID: u8 = 1;
+
+/// Default seconds after which a write is given up
+const DEFAULT_TIMEOUT: u64 = 3;
+
+/// Idle connections which are kept open
+const MAX_IDLE_CONNECTIONS: usize = 2;
+
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const EXCEPTION: u8 = 0x80;
+
+pub(crate) struct ModbusTrigger {
+    config: config::Modbus,
+    coils: HashMap<String, u16>,
+    transaction: AtomicU16,
+    idle: Mutex<Vec<TcpStream>>,
+    /// Granted entities per coil
+    holders: Holders<u16>,
+}
+
+impl ModbusTrigger {
+    pub(crate) fn new(config: config::Modbus) -> error::Result<ModbusTrigger> {
+        if config.coil.is_none() && config.coils.is_empty() {
+            return Err(error::new("modbus trigger needs a coil or coils".to_string()));
+        }
+
+        Ok(ModbusTrigger {
+            coils: config.coils.clone(),
+            config,
+            transaction: AtomicU16::new(0),
+            idle: Mutex::new(Vec::new()),
+            holders: Holders::default(),
+        })
+    }
+
+    fn get_coil(&self, decision: &Decision) -> Option<u16> {
+        self.coils.get(&decision.device)
+            .or(self.coils.get(&decision.zone))
+            .copied()
+            .or(self.config.coil)
+    }
+
+    async fn connect(&self) -> error::Result<TcpStream> {
+        let addr = format!("{}:{}", self.config.host, self.config.port.unwrap_or(DEFAULT_PORT));
+        debug!("Connecting to modbus {}", addr);
+
+        let stream = TcpStream::connect(&addr)
+            .await
+            .map_err(|e| error::new(format!("could not connect to modbus {}: {}", addr, e)))?;
+        stream.set_nodelay(true)
+            .map_err(|e| error::new(format!("could not configure modbus connection: {}", e)))?;
+
+        Ok(stream)
+    }
+
+    async fn write_coil(&self, stream: &mut TcpStream, coil: u16, on: bool) -> error::Result<()> {
+        let transaction = self.transaction.fetch_add(1, Ordering::Relaxed);
+
+        // MBAP header followed by the request
+        let mut request = [0u8; 12];
+        BE::write_u16(&mut request[0..2], transaction);
+        BE::write_u16(&mut request[4..6], 6);
+        request[6] = self.config.unit_id.unwrap_or(DEFAULT_UNIT_ID);
+        request[7] = WRITE_SINGLE_COIL;
+        BE::write_u16(&mut request[8..10], coil);
+        BE::write_u16(&mut request[10..12], if on { 0xFF00 } else { 0x0000 });
+
+        stream.write_all(&request)
+            .await
+            .map_err(|e| error::new(format!("could not write to modbus: {}", e)))?;
+
+        let mut header = [0u8; 7];
+        stream.read_exact(&mut header)
+            .await
+            .map_err(|e| error::new(format!("could not read from modbus: {}", e)))?;
+
+        let length = BE::read_u16(&header[4..6]) as usize;
+        if BE::read_u16(&header[0..2]) != transaction || !(2..=253).contains(&length) {
+            return Err(error::new("invalid modbus response header".to_string()));
+        }
+
+        let mut response = vec![0u8; length - 1];
+        stream.read_exact(&mut response)
+            .await
+            .map_err(|e| error::new(format!("could not read from modbus: {}", e)))?;
+
+        if response[0] == WRITE_SINGLE_COIL | EXCEPTION {
+            return Err(error::new(format!("modbus rejected writing coil {} with exception {}", coil, response.get(1).copied().unwrap_or(0))));
+        }
+
+        // A successful write is echoed
+        if response != request[7..] {
+            return Err(error::new(format!("unexpected modbus response for coil {}", coil)));
+        }
+
+        Ok(())
+    }
+
+    async fn switch(&self, coil: u16, on: bool) -> error::Result<()> {
+        let timeout = Duration::from_secs(self.config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+
+        let write = async {
+            let idle = self.idle.lock().await.pop();
+            if let Some(mut stream) = idle {
+                match self.write_coil(&mut stream, coil, on).await {
+                    Ok(()) => return Ok(stream),
+                    Err(err) => debug!("Reconnecting to modbus: {}", err),
+                }
+            }
+
+            let mut stream = self.connect().await?;
+            self.write_coil(&mut stream, coil, on).await?;
+
+            Ok::<TcpStream, error::Error>(stream)
+        };
+
+        // Broken connections are dropped
+        let stream = tokio::time::timeout(timeout, write)
+            .await
+            .map_err(|_| error::new(format!("modbus write of coil {} timed out", coil)))??;
+
+        let mut idle = self.idle.lock().await;
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for ModbusTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        let coil = match self.get_coil(decision) {
+            Some(coil) => coil,
+            None => return Ok(()),
+        };
+
+        self.holders.switch(coil, decision, |on| self.switch(coil, on)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, atomic::AtomicUsize};
+    use tokio::net::TcpListener;
+
+    /// Stand-in device answering every request with `reply`, closing each
+    /// connection after `requests` requests. Counts connections and requests.
+    async fn serve(reply: fn([u8; 12]) -> Vec<u8>, requests: usize) -> (ModbusTrigger, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let writes = Arc::new(AtomicUsize::new(0));
+
+        let (accepted, written) = (connections.clone(), writes.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                let written = written.clone();
+                tokio::spawn(async move {
+                    let mut request = [0u8; 12];
+                    for _ in 0..requests {
+                        if stream.read_exact(&mut request).await.is_err() {
+                            return;
+                        }
+                        written.fetch_add(1, Ordering::SeqCst);
+                        stream.write_all(&reply(request)).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let trigger = ModbusTrigger::new(config::Modbus {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            unit_id: None,
+            coil: Some(3),
+            coils: HashMap::new(),
+            timeout: Some(1),
+        }).unwrap();
+
+        (trigger, connections, writes)
+    }
+
+    #[tokio::test]
+    async fn echoed_write_succeeds() {
+        let (trigger, _, _) = serve(|request| request.to_vec(), usize::MAX).await;
+
+        assert!(trigger.switch(3, true).await.is_ok());
+        assert!(trigger.switch(3, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn different_echo_fails() {
+        // The device claims to have switched another coil
+        let (trigger, _, _) = serve(|mut request| {
+            request[9] += 1;
+            request.to_vec()
+        }, usize::MAX).await;
+
+        let err = trigger.switch(3, true).await.unwrap_err();
+        assert!(err.to_string().contains("unexpected modbus response"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn exception_fails() {
+        // Illegal data address
+        let (trigger, _, _) = serve(|request| {
+            let mut response = request[..7].to_vec();
+            BE::write_u16(&mut response[4..6], 3);
+            response.extend_from_slice(&[WRITE_SINGLE_COIL | EXCEPTION, 0x02]);
+            response
+        }, usize::MAX).await;
+
+        let err = trigger.switch(3, true).await.unwrap_err();
+        assert!(err.to_string().contains("exception 2"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn broken_idle_connection_is_replaced() {
+        // The device closes every connection after one write
+        let (trigger, connections, _) = serve(|request| request.to_vec(), 1).await;
+
+        assert!(trigger.switch(3, true).await.is_ok());
+        assert!(trigger.switch(3, false).await.is_ok());
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn shared_coil_follows_granted_entities() {
+        let (trigger, _, writes) = serve(|request| request.to_vec(), usize::MAX).await;
+
+        let decision = |device: &str| {
+            let mut decision = Decision::new(device.to_string(), "door".to_string(), format!("lock.{}", device));
+            decision.known = true;
+            decision
+        };
+
+        // Only the first grant sets the coil
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("b").grant()).await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        // The coil stays set while another device is granted
+        trigger.publish(&decision("a").release("absent")).await.unwrap();
+        trigger.publish(&decision("c").release("absent")).await.unwrap();
+        trigger.publish(&decision("b").deny("lockdown")).await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        trigger.publish(&decision("b").release("absent")).await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+    }
+}
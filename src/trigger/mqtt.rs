@@ -0,0 +1,330 @@
+//! MQTT backend with Home Assistant MQTT discovery.
+//!
+//! Every known device gets a `binary_sensor` announced below the discovery prefix,
+//! its state and attributes are published retained below the topic prefix. The
+//! availability topic is set to `offline` by the last will when the controller
+//! disappears. The `device_class` and `icon` of a device are part of its
+//! discovery config.
+
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::{error, config};
+
+use super::{Decision, Outcome, TriggerBackend, get_object_id, home_assistant::{EntityConfig, get_entity_configs}};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_CLIENT_ID: &str = "ble-fencer";
+const DEFAULT_TOPIC_PREFIX: &str = "ble-fencer";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Maximum seconds to wait between reconnects
+const MAX_BACKOFF: u64 = 60;
+
+pub(crate) struct MqttTrigger {
+    client: AsyncClient,
+    client_id: String,
+    topic_prefix: String,
+    discovery_prefix: String,
+    /// Entity settings per device
+    entities: HashMap<String, EntityConfig>,
+    /// Entities which were announced since the last (re)connect
+    announced: Arc<Mutex<HashSet<String>>>,
+}
+
+async fn run_event_loop(mut event_loop: EventLoop, client: AsyncClient, availability_topic: String, discovery_prefix: String, announced: Arc<Mutex<HashSet<String>>>) {
+    let mut backoff = 1;
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                backoff = 1;
+
+                // Announce everything again, the broker might have lost the retained messages
+                announced.lock().unwrap().clear();
+
+                if let Err(err) = client.try_publish(availability_topic.clone(), QoS::AtLeastOnce, true, "online") {
+                    warn!("Could not publish MQTT availability: {}", err);
+                }
+                if let Err(err) = client.try_subscribe(format!("{}/status", discovery_prefix), QoS::AtLeastOnce) {
+                    warn!("Could not subscribe to Home Assistant status: {}", err);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                // Home Assistant restarted and needs the discovery again
+                if publish.payload.as_ref() == b"online" {
+                    debug!("Home Assistant came online, announcing entities again");
+                    announced.lock().unwrap().clear();
+                }
+            }
+            Ok(_) => (),
+            Err(err) => {
+                warn!("MQTT connection failed, reconnecting in {}s: {}", backoff, err);
+                tokio::time::sleep(Duration::from_secs(backoff)).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+impl MqttTrigger {
+    pub(crate) fn new(config: config::Mqtt, devices: &HashMap<String, config::Device>) -> MqttTrigger {
+        let client_id = config.client_id.unwrap_or(DEFAULT_CLIENT_ID.to_string());
+        let topic_prefix = config.topic_prefix.unwrap_or(DEFAULT_TOPIC_PREFIX.to_string());
+        let discovery_prefix = config.discovery_prefix.unwrap_or(DEFAULT_DISCOVERY_PREFIX.to_string());
+        let availability_topic = format!("{}/status", topic_prefix);
+
+        let mut options = MqttOptions::new(client_id.clone(), config.host, config.port.unwrap_or(DEFAULT_PORT));
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(availability_topic.clone(), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = config.username {
+            options.set_credentials(username, config.password.unwrap_or_default());
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, 100);
+        let announced = Arc::new(Mutex::new(HashSet::new()));
+
+        tokio::spawn(run_event_loop(event_loop, client.clone(), availability_topic, discovery_prefix.clone(), announced.clone()));
+
+        MqttTrigger {
+            client,
+            client_id,
+            topic_prefix,
+            discovery_prefix,
+            entities: get_entity_configs(devices),
+            announced,
+        }
+    }
+
+    /// Discovery topic and config of an entity
+    fn get_discovery(&self, object_id: &str, decision: &Decision) -> (String, Value) {
+        let mut payload = json!({
+            "name": decision.name,
+            "unique_id": format!("{}_{}", self.client_id, object_id),
+            "object_id": object_id,
+            "state_topic": format!("{}/{}/state", self.topic_prefix, object_id),
+            "json_attributes_topic": format!("{}/{}/attributes", self.topic_prefix, object_id),
+            "availability_topic": format!("{}/status", self.topic_prefix),
+            "payload_on": "ON",
+            "payload_off": "OFF",
+            "device": {
+                "identifiers": [self.client_id],
+                "name": self.client_id,
+                "manufacturer": "BLE fencer",
+            },
+        });
+
+        if let Some(entity_config) = self.entities.get(&decision.device) {
+            if let Some(device_class) = &entity_config.device_class {
+                payload["device_class"] = json!(device_class);
+            }
+            if let Some(icon) = &entity_config.icon {
+                payload["icon"] = json!(icon);
+            }
+        }
+
+        (format!("{}/binary_sensor/{}/{}/config", self.discovery_prefix, self.client_id, object_id), payload)
+    }
+
+    fn announce(&self, object_id: &str, decision: &Decision) -> error::Result<()> {
+        if self.announced.lock().unwrap().contains(object_id) {
+            return Ok(());
+        }
+
+        let (topic, payload) = self.get_discovery(object_id, decision);
+        self.client.try_publish(topic, QoS::AtLeastOnce, true, payload.to_string())
+            .map_err(|e| error::new(format!("could not publish MQTT discovery: {}", e)))?;
+
+        self.announced.lock().unwrap().insert(object_id.to_string());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for MqttTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        // Unknown devices would flood Home Assistant with entities
+        if !decision.known {
+            return Ok(());
+        }
+
+        let object_id = get_object_id(&decision.entity);
+        self.announce(&object_id, decision)?;
+
+        let state = match decision.outcome {
+            Outcome::Granted => "ON",
+            Outcome::Denied | Outcome::Released => "OFF",
+        };
+
+        let attributes = serde_json::to_string(decision)
+            .map_err(|e| error::new(format!("could not serialize decision: {}", e)))?;
+
+        self.client.try_publish(format!("{}/{}/attributes", self.topic_prefix, object_id), QoS::AtLeastOnce, true, attributes)
+            .map_err(|e| error::new(format!("could not publish MQTT attributes: {}", e)))?;
+        self.client.try_publish(format!("{}/{}/state", self.topic_prefix, object_id), QoS::AtLeastOnce, true, state)
+            .map_err(|e| error::new(format!("could not publish MQTT state: {}", e)))?;
+
+        Ok(())
+    }
+
+    // Retained states only describe the device, queued grants open nothing
+    fn retries(&self, _decision: &Decision) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::mpsc};
+
+    /// Packet received by the stand-in broker, the last will is reported like a publish
+    #[derive(Debug, PartialEq)]
+    struct Received {
+        will: bool,
+        topic: String,
+        payload: String,
+        retain: bool,
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = stream.read_u8().await.ok()?;
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await.ok()?;
+
+        Some((header, body))
+    }
+
+    fn take_string(body: &mut &[u8]) -> String {
+        let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let string = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+        *body = &body[2 + length..];
+
+        string
+    }
+
+    /// Stand-in broker accepting one client and reporting everything it publishes
+    async fn serve() -> (u16, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            while let Some((header, body)) = read_packet(&mut stream).await {
+                let mut rest = body.as_slice();
+
+                match header >> 4 {
+                    // CONNECT with protocol name, level, flags, keep alive and client id
+                    1 => {
+                        take_string(&mut rest);
+                        let flags = rest[1];
+                        rest = &rest[4..];
+                        take_string(&mut rest);
+
+                        if flags & 0x04 != 0 {
+                            let topic = take_string(&mut rest);
+                            let payload = take_string(&mut rest);
+                            sender.send(Received { will: true, topic, payload, retain: flags & 0x20 != 0 }).unwrap();
+                        }
+                        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+                    }
+                    // PUBLISH, acknowledged for QoS 1
+                    3 => {
+                        let topic = take_string(&mut rest);
+                        if header & 0x06 != 0 {
+                            stream.write_all(&[0x40, 0x02, rest[0], rest[1]]).await.unwrap();
+                            rest = &rest[2..];
+                        }
+
+                        let payload = String::from_utf8(rest.to_vec()).unwrap();
+                        sender.send(Received { will: false, topic, payload, retain: header & 0x01 != 0 }).unwrap();
+                    }
+                    // SUBSCRIBE
+                    8 => stream.write_all(&[0x90, 0x03, rest[0], rest[1], 0x01]).await.unwrap(),
+                    // PINGREQ
+                    12 => stream.write_all(&[0xD0, 0x00]).await.unwrap(),
+                    _ => (),
+                }
+            }
+        });
+
+        (port, receiver)
+    }
+
+    async fn next(receiver: &mut mpsc::UnboundedReceiver<Received>) -> Received {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap()
+    }
+
+    fn trigger(port: u16) -> MqttTrigger {
+        let config: config::Mqtt = serde_yaml::from_str(&format!("host: 127.0.0.1\nport: {}", port)).unwrap();
+        let device: config::Device = serde_yaml::from_str("key: \"\"\nname: Front door\ndevice_id: \"\"\nmanufacture: 89\ncutoff_rssi: -99\nallowed_times: {}\ndevice_class: door\nicon: mdi:door").unwrap();
+
+        MqttTrigger::new(config, &HashMap::from([("AA:BB:CC:DD:EE:FF".to_string(), device)]))
+    }
+
+    fn decision() -> Decision {
+        let mut decision = Decision::new("AA:BB:CC:DD:EE:FF".to_string(), "Front Door".to_string(), "AA:BB:CC:DD:EE:FF Front Door".to_string());
+        decision.name = "Front door".to_string();
+        decision.known = true;
+        decision.grant()
+    }
+
+    #[tokio::test]
+    async fn availability_uses_last_will() {
+        let (port, mut receiver) = serve().await;
+        let _trigger = trigger(port);
+
+        assert_eq!(next(&mut receiver).await, Received { will: true, topic: "ble-fencer/status".to_string(), payload: "offline".to_string(), retain: true });
+        assert_eq!(next(&mut receiver).await, Received { will: false, topic: "ble-fencer/status".to_string(), payload: "online".to_string(), retain: true });
+    }
+
+    #[tokio::test]
+    async fn decisions_are_announced_and_published() {
+        let (port, mut receiver) = serve().await;
+        let trigger = trigger(port);
+        next(&mut receiver).await;
+        next(&mut receiver).await;
+
+        trigger.publish(&decision()).await.unwrap();
+
+        let discovery = next(&mut receiver).await;
+        assert_eq!(discovery.topic, "homeassistant/binary_sensor/ble-fencer/aa_bb_cc_dd_ee_ff_front_door/config");
+        assert!(discovery.retain);
+
+        let payload: Value = serde_json::from_str(&discovery.payload).unwrap();
+        assert_eq!(payload["unique_id"], "ble-fencer_aa_bb_cc_dd_ee_ff_front_door");
+        assert_eq!(payload["state_topic"], "ble-fencer/aa_bb_cc_dd_ee_ff_front_door/state");
+        assert_eq!(payload["availability_topic"], "ble-fencer/status");
+        assert_eq!(payload["device_class"], "door");
+        assert_eq!(payload["icon"], "mdi:door");
+
+        let attributes = next(&mut receiver).await;
+        assert_eq!(attributes.topic, "ble-fencer/aa_bb_cc_dd_ee_ff_front_door/attributes");
+        assert_eq!(serde_json::from_str::<Value>(&attributes.payload).unwrap()["outcome"], "granted");
+
+        let state = next(&mut receiver).await;
+        assert_eq!(state, Received { will: false, topic: "ble-fencer/aa_bb_cc_dd_ee_ff_front_door/state".to_string(), payload: "ON".to_string(), retain: true });
+
+        // The discovery is only sent once per connection
+        trigger.publish(&decision().release("absent")).await.unwrap();
+        assert_eq!(next(&mut receiver).await.topic, "ble-fencer/aa_bb_cc_dd_ee_ff_front_door/attributes");
+        assert_eq!(next(&mut receiver).await.payload, "OFF");
+    }
+}
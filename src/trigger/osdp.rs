@@ -0,0 +1,452 @@
+//! OSDP peripheral, so an access control panel sees grants as card reads.
+//!
+//! The controller answers the polls of the panel as a card reader over a serial
+//! port or a TCP connection accepted on `listen`. When the entity of a device
+//! with a `credential` becomes granted, a card read is queued and reported with
+//! the next poll as raw card data, as Wiegand 26 bit (H10301) if a
+//! `facility_code` is configured, else as a 32 bit number. The secure channel
+//! is not supported.
+
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::OpenOptions, os::unix::io::AsRawFd, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use nix::sys::termios;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpListener};
+
+use crate::{error, config};
+
+use super::{Decision, Outcome, TriggerBackend};
+
+const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_ADDRESS: u8 = 0;
+
+/// Seconds after which a card read not fetched by the panel is dropped
+const CARD_READ_TIMEOUT: u64 = 5;
+
+/// Card reads which are queued at most
+const MAX_CARD_READS: usize = 16;
+
+/// Maximum seconds to wait between reopening the serial port
+const MAX_BACKOFF: u64 = 60;
+
+const SOM: u8 = 0x53;
+const BROADCAST: u8 = 0x7F;
+const REPLY: u8 = 0x80;
+const MAX_PACKET: usize = 1440;
+
+const CTRL_SQN: u8 = 0x03;
+const CTRL_CRC: u8 = 0x04;
+const CTRL_SCB: u8 = 0x08;
+
+const CMD_POLL: u8 = 0x60;
+const CMD_ID: u8 = 0x61;
+const CMD_CAP: u8 = 0x62;
+const CMD_LSTAT: u8 = 0x64;
+const CMD_OUT: u8 = 0x68;
+const CMD_LED: u8 = 0x69;
+const CMD_BUZ: u8 = 0x6A;
+const CMD_TEXT: u8 = 0x6B;
+
+const REPLY_ACK: u8 = 0x40;
+const REPLY_NAK: u8 = 0x41;
+const REPLY_PDID: u8 = 0x45;
+const REPLY_PDCAP: u8 = 0x46;
+const REPLY_LSTATR: u8 = 0x48;
+const REPLY_RAW: u8 = 0x50;
+
+const NAK_UNKNOWN_COMMAND: u8 = 0x03;
+const NAK_SECURITY_BLOCK: u8 = 0x05;
+
+const FORMAT_UNSPECIFIED: u8 = 0x00;
+const FORMAT_WIEGAND: u8 = 0x01;
+
+type CardReads = Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>;
+
+pub(crate) struct OsdpTrigger {
+    facility_code: Option<u8>,
+    /// Credential number per device
+    credentials: HashMap<String, u32>,
+    card_reads: CardReads,
+    /// Entities whose last decision was a grant
+    granted: Mutex<HashSet<String>>,
+}
+
+/// CRC-16 with the polynomial 0x1021 and the initial value 0x1D0F
+fn get_crc(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0x1D0F;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
+
+fn get_checksum(data: &[u8]) -> u8 {
+    0u8.wrapping_sub(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)))
+}
+
+/// Data of an `osdp_RAW` reply for the credential
+fn get_card_data(credential: u32, facility_code: Option<u8>) -> Vec<u8> {
+    let (format, bits, value) = match facility_code {
+        Some(facility_code) => {
+            let data = ((facility_code as u32) << 16) | (credential & 0xFFFF);
+            let even = (data >> 12).count_ones() % 2;
+            let odd = 1 - (data & 0xFFF).count_ones() % 2;
+
+            // Left aligned, the first bit is the most significant one
+            (FORMAT_WIEGAND, 26u16, ((even << 25) | (data << 1) | odd) << 6)
+        }
+        None => (FORMAT_UNSPECIFIED, 32u16, credential),
+    };
+
+    let mut card_data = vec![0, format];
+    card_data.extend_from_slice(&bits.to_le_bytes());
+    card_data.extend_from_slice(&value.to_be_bytes());
+
+    card_data
+}
+
+fn get_reply(address: u8, ctrl: u8, code: u8, data: &[u8]) -> Vec<u8> {
+    let crc = ctrl & CTRL_CRC != 0;
+    let length = 6 + data.len() + if crc { 2 } else { 1 };
+
+    let mut reply = vec![SOM, address | REPLY];
+    reply.extend_from_slice(&(length as u16).to_le_bytes());
+    reply.push(ctrl & (CTRL_SQN | CTRL_CRC));
+    reply.push(code);
+    reply.extend_from_slice(data);
+
+    if crc {
+        let crc = get_crc(&reply);
+        reply.extend_from_slice(&crc.to_le_bytes());
+    } else {
+        reply.push(get_checksum(&reply));
+    }
+
+    reply
+}
+
+fn take_card_read(card_reads: &CardReads) -> Option<Vec<u8>> {
+    let mut card_reads = card_reads.lock().unwrap();
+
+    while let Some((queued, card_data)) = card_reads.pop_front() {
+        if queued.elapsed() < Duration::from_secs(CARD_READ_TIMEOUT) {
+            return Some(card_data);
+        }
+
+        debug!("Dropping card read which was not polled in time");
+    }
+
+    None
+}
+
+fn handle(address: u8, ctrl: u8, code: u8, card_reads: &CardReads) -> Vec<u8> {
+    if ctrl & CTRL_SCB != 0 {
+        return get_reply(address, ctrl, REPLY_NAK, &[NAK_SECURITY_BLOCK]);
+    }
+
+    match code {
+        CMD_POLL => match take_card_read(card_reads) {
+            Some(card_data) => get_reply(address, ctrl, REPLY_RAW, &card_data),
+            None => get_reply(address, ctrl, REPLY_ACK, &[]),
+        },
+        CMD_ID => get_reply(address, ctrl, REPLY_PDID, &[0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 1, 0]),
+        // Card data as bit array and CRC support
+        CMD_CAP => get_reply(address, ctrl, REPLY_PDCAP, &[4, 1, 0, 8, 1, 0]),
+        CMD_LSTAT => get_reply(address, ctrl, REPLY_LSTATR, &[0, 0]),
+        // There is nothing to show, but panels expect the reader to accept these
+        CMD_OUT | CMD_LED | CMD_BUZ | CMD_TEXT => get_reply(address, ctrl, REPLY_ACK, &[]),
+        _ => get_reply(address, ctrl, REPLY_NAK, &[NAK_UNKNOWN_COMMAND]),
+    }
+}
+
+/// Answers the commands of the panel until the connection fails
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, address: u8, card_reads: &CardReads) -> error::Result<()> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut last_reply: Option<(u8, Vec<u8>)> = None;
+    let mut chunk = [0u8; 256];
+
+    loop {
+        let read = stream.read(&mut chunk)
+            .await
+            .map_err(|e| error::new(format!("could not read from panel: {}", e)))?;
+        if read == 0 {
+            return Err(error::new("panel closed the connection".to_string()));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        loop {
+            // Skip noise until the next start of message
+            match buffer.iter().position(|byte| *byte == SOM) {
+                Some(start) => { buffer.drain(..start); }
+                None => { buffer.clear(); }
+            }
+            if buffer.len() < 6 {
+                break;
+            }
+
+            let length = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+            let ctrl = buffer[4];
+            let check_length = if ctrl & CTRL_CRC != 0 { 2 } else { 1 };
+            if length < 6 + check_length || length > MAX_PACKET {
+                buffer.remove(0);
+                continue;
+            }
+            if buffer.len() < length {
+                break;
+            }
+
+            let (body, check) = buffer[..length].split_at(length - check_length);
+            let valid = if check_length == 2 {
+                get_crc(body).to_le_bytes() == check
+            } else {
+                get_checksum(body) == check[0]
+            };
+            if !valid {
+                // The start of message might have been noise as well
+                debug!("Dropping OSDP packet with invalid check");
+                buffer.remove(0);
+                continue;
+            }
+
+            let packet: Vec<u8> = buffer.drain(..length).collect();
+            let body = &packet[..length - check_length];
+
+            let packet_address = body[1] & !REPLY;
+            if body[1] & REPLY != 0 || (packet_address != address && packet_address != BROADCAST) {
+                continue;
+            }
+
+            // The panel repeats a command if it missed our reply
+            let sqn = ctrl & CTRL_SQN;
+            let reply = match &last_reply {
+                Some((last_sqn, reply)) if sqn != 0 && *last_sqn == sqn => reply.clone(),
+                _ => handle(address, ctrl, body[5], card_reads),
+            };
+
+            stream.write_all(&reply)
+                .await
+                .map_err(|e| error::new(format!("could not write to panel: {}", e)))?;
+            last_reply = Some((sqn, reply));
+        }
+    }
+}
+
+fn get_baud_rate(baud_rate: u32) -> error::Result<termios::BaudRate> {
+    match baud_rate {
+        9600 => Ok(termios::BaudRate::B9600),
+        19200 => Ok(termios::BaudRate::B19200),
+        38400 => Ok(termios::BaudRate::B38400),
+        57600 => Ok(termios::BaudRate::B57600),
+        115200 => Ok(termios::BaudRate::B115200),
+        230400 => Ok(termios::BaudRate::B230400),
+        _ => Err(error::new(format!("unsupported OSDP baud rate {}", baud_rate))),
+    }
+}
+
+fn open_serial(path: &str, baud_rate: termios::BaudRate) -> error::Result<tokio::fs::File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| error::new(format!("could not open serial port {}: {}", path, e)))?;
+
+    let mut settings = termios::tcgetattr(file.as_raw_fd())
+        .map_err(|e| error::new(format!("could not read settings of {}: {}", path, e)))?;
+    termios::cfmakeraw(&mut settings);
+    termios::cfsetspeed(&mut settings, baud_rate)
+        .map_err(|e| error::new(format!("could not set baud rate of {}: {}", path, e)))?;
+    settings.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 1;
+    settings.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 0;
+    termios::tcsetattr(file.as_raw_fd(), termios::SetArg::TCSANOW, &settings)
+        .map_err(|e| error::new(format!("could not configure {}: {}", path, e)))?;
+
+    Ok(tokio::fs::File::from_std(file))
+}
+
+async fn run_serial(path: String, baud_rate: termios::BaudRate, address: u8, card_reads: CardReads) {
+    let mut backoff = 1;
+
+    loop {
+        let res = match open_serial(&path, baud_rate) {
+            Ok(file) => {
+                info!("Serving OSDP on {}", path);
+                backoff = 1;
+                serve(file, address, &card_reads).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = res {
+            warn!("OSDP on {} failed, reopening in {}s: {}", path, backoff, err);
+        }
+
+        tokio::time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn run_tcp(listen: String, address: u8, card_reads: CardReads) {
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Could not listen for OSDP on {}: {}", listen, err);
+            return;
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                info!("Panel {} connected for OSDP", peer);
+
+                // A panel polls its readers one at a time, so one connection is served at once
+                if let Err(err) = serve(stream, address, &card_reads).await {
+                    warn!("OSDP connection of {} failed: {}", peer, err);
+                }
+            }
+            Err(err) => warn!("Could not accept OSDP connection: {}", err),
+        }
+    }
+}
+
+impl OsdpTrigger {
+    pub(crate) fn new(config: config::Osdp, devices: &HashMap<String, config::Device>) -> error::Result<OsdpTrigger> {
+        let credentials: HashMap<String, u32> = devices.iter()
+            .filter_map(|(addr, device_config)| device_config.credential.map(|credential| (addr.clone(), credential)))
+            .collect();
+
+        // Wiegand 26 bit only carries 16 bit card numbers
+        if config.facility_code.is_some() {
+            if let Some((addr, _)) = credentials.iter().find(|(_, credential)| **credential > 0xFFFF) {
+                return Err(error::new(format!("credential of {} does not fit into Wiegand 26 bit", addr)));
+            }
+        }
+
+        let address = config.address.unwrap_or(DEFAULT_ADDRESS);
+        if address >= BROADCAST {
+            return Err(error::new(format!("invalid OSDP address {}", address)));
+        }
+
+        let card_reads = CardReads::default();
+        match (config.serial, config.listen) {
+            (Some(serial), None) => {
+                let baud_rate = get_baud_rate(config.baud_rate.unwrap_or(DEFAULT_BAUD_RATE))?;
+                tokio::spawn(run_serial(serial, baud_rate, address, card_reads.clone()));
+            }
+            (None, Some(listen)) => {
+                tokio::spawn(run_tcp(listen, address, card_reads.clone()));
+            }
+            _ => return Err(error::new("OSDP trigger needs either serial or listen".to_string())),
+        }
+
+        Ok(OsdpTrigger {
+            facility_code: config.facility_code,
+            credentials,
+            card_reads,
+            granted: Mutex::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for OsdpTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        let credential = match self.credentials.get(&decision.device) {
+            Some(credential) => *credential,
+            None => return Ok(()),
+        };
+
+        if decision.outcome != Outcome::Granted {
+            self.granted.lock().unwrap().remove(&decision.entity);
+            return Ok(());
+        }
+        if !self.granted.lock().unwrap().insert(decision.entity.clone()) {
+            return Ok(());
+        }
+
+        // A late card read would open the door long after the tag left, so it is not retried
+        let mut card_reads = self.card_reads.lock().unwrap();
+        if card_reads.len() >= MAX_CARD_READS {
+            warn!("Dropping card read of {}, the panel does not poll", decision.device);
+            return Ok(());
+        }
+
+        debug!("Queueing card read of {} for {}", credential, decision.device);
+        card_reads.push_back((Instant::now(), get_card_data(credential, self.facility_code)));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    /// osdp_POLL to address 0 with sequence number 1 and CRC
+    const POLL_SQN_1: [u8; 8] = [0x53, 0x00, 0x08, 0x00, 0x05, 0x60, 0xDA, 0x99];
+    /// osdp_POLL to address 0 with sequence number 2 and CRC
+    const POLL_SQN_2: [u8; 8] = [0x53, 0x00, 0x08, 0x00, 0x06, 0x60, 0x89, 0xCC];
+    /// osdp_ACK of address 0 to `POLL_SQN_1`
+    const ACK_SQN_1: [u8; 8] = [0x53, 0x80, 0x08, 0x00, 0x05, 0x40, 0x68, 0x9F];
+
+    async fn exchange(panel: &mut DuplexStream, command: &[u8], reply_length: usize) -> Vec<u8> {
+        panel.write_all(command).await.unwrap();
+
+        let mut reply = vec![0u8; reply_length];
+        tokio::time::timeout(Duration::from_secs(1), panel.read_exact(&mut reply)).await.unwrap().unwrap();
+
+        reply
+    }
+
+    #[test]
+    fn crc_matches_known_frames() {
+        // Check value of CRC-16/AUG-CCITT
+        assert_eq!(get_crc(b"123456789"), 0xE5CC);
+        assert_eq!(get_crc(&POLL_SQN_1[..6]).to_le_bytes(), POLL_SQN_1[6..]);
+        assert_eq!(get_crc(&ACK_SQN_1[..6]).to_le_bytes(), ACK_SQN_1[6..]);
+    }
+
+    #[test]
+    fn checksum_matches_known_frame() {
+        // osdp_POLL to address 0 without sequence number
+        let poll = [0x53, 0x00, 0x07, 0x00, 0x00, 0x60, 0x46];
+
+        assert_eq!(get_checksum(&poll[..6]), poll[6]);
+        assert_eq!(get_reply(0, 0x00, REPLY_ACK, &[]), [0x53, 0x80, 0x07, 0x00, 0x00, 0x40, 0xE6]);
+    }
+
+    #[test]
+    fn card_data_has_wiegand_parity() {
+        // H10301 facility code 18 and card 12345 is 0 00010010 0011000000111001 1
+        assert_eq!(get_card_data(12345, Some(18)), [0x00, FORMAT_WIEGAND, 26, 0x00, 0x09, 0x18, 0x1C, 0xC0]);
+        assert_eq!(get_card_data(12345, None), [0x00, FORMAT_UNSPECIFIED, 32, 0x00, 0x00, 0x00, 0x30, 0x39]);
+    }
+
+    #[tokio::test]
+    async fn polls_are_answered() {
+        let card_reads = CardReads::default();
+        let (mut panel, reader) = tokio::io::duplex(MAX_PACKET);
+        let served = card_reads.clone();
+        tokio::spawn(async move { serve(reader, 0, &served).await });
+
+        // Nothing to report
+        assert_eq!(exchange(&mut panel, &POLL_SQN_1, ACK_SQN_1.len()).await, ACK_SQN_1);
+
+        // A queued card read is reported once
+        let card_data = get_card_data(12345, Some(18));
+        card_reads.lock().unwrap().push_back((Instant::now(), card_data.clone()));
+        let raw = get_reply(0, POLL_SQN_2[4], REPLY_RAW, &card_data);
+        assert_eq!(exchange(&mut panel, &POLL_SQN_2, raw.len()).await, raw);
+
+        // The panel missed the reply and repeats the poll
+        assert_eq!(exchange(&mut panel, &POLL_SQN_2, raw.len()).await, raw);
+        assert!(card_reads.lock().unwrap().is_empty());
+    }
+}
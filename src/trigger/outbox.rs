@@ -0,0 +1,218 @@
+//! Persistent outbox for failed deliveries.
+//!
+//! Decisions of known devices which could not be delivered are stored in the
+//! `outbox` table, one row per backend and entity, so only the latest state is
+//! retried. Grants are only retried by backends mirroring states, since opening
+//! a door minutes late is worse than not opening it. Retries back off
+//! exponentially. A circuit breaker per backend skips direct deliveries to a
+//! backend which keeps failing, so a dead backend does not slow down the scan
+//! loop.
+
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use log::{debug, info, warn};
+
+use crate::{error, database};
+
+use super::{Decision, Triggers};
+
+/// Consecutive failures after which the breaker opens
+const BREAKER_THRESHOLD: u32 = 3;
+
+/// Seconds the breaker stays open before letting a delivery through again
+const BREAKER_COOLDOWN: u64 = 30;
+
+/// Maximum seconds between retries of a delivery
+const MAX_BACKOFF: u64 = 300;
+
+#[derive(Default)]
+pub(crate) struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Checks if a delivery should be attempted
+    pub(crate) fn is_closed(&self) -> bool {
+        match self.open_until {
+            Some(open_until) => Instant::now() >= open_until,
+            None => true,
+        }
+    }
+
+    pub(crate) fn success(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    /// Records a failure and returns true if the breaker opened
+    pub(crate) fn failure(&mut self) -> bool {
+        self.failures += 1;
+
+        if self.failures >= BREAKER_THRESHOLD {
+            let was_closed = self.open_until.is_none();
+            self.open_until = Some(Instant::now() + Duration::from_secs(BREAKER_COOLDOWN));
+            return was_closed;
+        }
+
+        false
+    }
+}
+
+pub(crate) async fn enqueue(database_path: String, backend: &str, decision: &Decision) -> error::Result<()> {
+    let payload = serde_json::to_string(decision)
+        .map_err(|e| error::new(format!("could not serialize decision: {}", e)))?;
+    let now = chrono::Utc::now().timestamp() as u64;
+
+    database::queue_outbox(database_path, backend.to_string(), decision.entity.clone(), payload, now + get_backoff(0)).await
+}
+
+fn get_backoff(attempts: u32) -> u64 {
+    2u64.saturating_pow(attempts).min(MAX_BACKOFF)
+}
+
+async fn retry_due(triggers: &Triggers, now: u64) -> error::Result<()> {
+    let entries = database::get_due_outbox(triggers.database_path.clone(), now).await?;
+
+    for entry in entries {
+        let backend = match triggers.backends.iter().find(|backend| backend.name == entry.backend) {
+            Some(backend) => backend,
+            None => {
+                // The backend was removed from the config
+                database::delete_outbox(triggers.database_path.clone(), entry.backend, entry.entity).await?;
+                continue;
+            }
+        };
+
+        if !backend.breaker.lock().unwrap().is_closed() {
+            continue;
+        }
+
+        let decision: Decision = serde_json::from_str(&entry.decision)
+            .map_err(|e| error::new(format!("invalid decision in outbox: {}", e)))?;
+
+        // Grants queued before the backend stopped retrying them are stale
+        if !backend.is_queued(&decision) {
+            database::delete_outbox(triggers.database_path.clone(), entry.backend, entry.entity).await?;
+            continue;
+        }
+
+        match backend.backend.publish(&decision).await {
+            Ok(()) => {
+                info!("Delivered queued {:?} for {} to {} after {} retries", decision.outcome, entry.entity, entry.backend, entry.attempts + 1);
+                backend.breaker.lock().unwrap().success();
+                database::delete_retried_outbox(triggers.database_path.clone(), entry).await?;
+            }
+            Err(err) => {
+                debug!("Retry of {} for {} failed: {}", entry.backend, entry.entity, err);
+                backend.breaker.lock().unwrap().failure();
+
+                let attempts = entry.attempts + 1;
+                database::reschedule_outbox(triggers.database_path.clone(), entry, attempts, now + get_backoff(attempts)).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Retries queued deliveries until the controller stops
+pub(crate) async fn retry(triggers: Arc<Triggers>) {
+    loop {
+        if let Err(err) = retry_due(&triggers, chrono::Utc::now().timestamp() as u64).await {
+            warn!("Could not retry outbox: {}", err);
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+    use async_trait::async_trait;
+    use crate::trigger::{Backend, TriggerBackend};
+
+    /// Backend which fails while `failing` is set
+    struct Flaky {
+        failing: Arc<AtomicBool>,
+        published: Arc<Mutex<Vec<Decision>>>,
+    }
+
+    #[async_trait]
+    impl TriggerBackend for Flaky {
+        async fn publish(&self, decision: &Decision) -> error::Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(error::new("backend is down".to_string()));
+            }
+
+            self.published.lock().unwrap().push(decision.clone());
+            Ok(())
+        }
+    }
+
+    async fn triggers(name: &str) -> (Triggers, Arc<AtomicBool>, Arc<Mutex<Vec<Decision>>>) {
+        let failing = Arc::new(AtomicBool::new(true));
+        let published = Arc::new(Mutex::new(Vec::new()));
+
+        let triggers = Triggers {
+            backends: vec![Backend {
+                name: "flaky".to_string(),
+                backend: Box::new(Flaky { failing: failing.clone(), published: published.clone() }),
+                breaker: Mutex::default(),
+            }],
+            home_assistant_states: None,
+            database_path: database::tests::create(name).await,
+            failed_over: Mutex::default(),
+        };
+
+        (triggers, failing, published)
+    }
+
+    fn decision() -> Decision {
+        let mut decision = Decision::new("AA:BB:CC:DD:EE:FF".to_string(), "door".to_string(), "AA:BB:CC:DD:EE:FF".to_string());
+        decision.known = true;
+        decision
+    }
+
+    #[tokio::test]
+    async fn backoff_grows_while_backend_fails() {
+        let (triggers, _, _) = triggers("outbox-backoff").await;
+        let now = chrono::Utc::now().timestamp() as u64;
+
+        assert!(triggers.deliver(&triggers.backends[0], &decision().deny("first"), true).await.is_err());
+        retry_due(&triggers, now + 2).await.unwrap();
+
+        // A newer decision replaces the queued one but keeps its backoff
+        assert!(triggers.deliver(&triggers.backends[0], &decision().deny("second"), true).await.is_err());
+
+        assert!(database::get_due_outbox(triggers.database_path.clone(), now + 3).await.unwrap().is_empty());
+        let entries = database::get_due_outbox(triggers.database_path.clone(), now + 4).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].attempts, 1);
+        assert!(entries[0].decision.contains("second"));
+    }
+
+    #[tokio::test]
+    async fn newer_delivery_drops_queued_decision() {
+        let (triggers, failing, published) = triggers("outbox-stale").await;
+        let later = chrono::Utc::now().timestamp() as u64 + 1000;
+
+        assert!(triggers.deliver(&triggers.backends[0], &decision().release("absent"), true).await.is_err());
+        let entries = database::get_due_outbox(triggers.database_path.clone(), later).await.unwrap();
+
+        failing.store(false, Ordering::SeqCst);
+        assert!(triggers.deliver(&triggers.backends[0], &decision().deny("lockdown"), true).await.unwrap());
+
+        // A retry which was already running must not bring the stale decision back
+        for entry in entries {
+            database::reschedule_outbox(triggers.database_path.clone(), entry, 1, later).await.unwrap();
+        }
+
+        retry_due(&triggers, later).await.unwrap();
+        let published = published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].reason.as_deref(), Some("lockdown"));
+    }
+}
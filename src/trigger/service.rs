@@ -0,0 +1,91 @@
+//! Calls Home Assistant services (`lock.unlock`, `switch.turn_on`, `script.*`)
+//! instead of writing states of entities Home Assistant does not own.
+//!
+//! The `on` service is called for grants and the optional `off` service when a
+//! device is released. Denials do not call any service. While an entity stays
+//! granted the `on` service is not called again.
+
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use async_trait::async_trait;
+use log::debug;
+use serde_json::Value;
+
+use crate::{error, config};
+
+use super::{Decision, Outcome, TriggerBackend};
+
+pub(crate) struct ServiceTrigger {
+    config: config::HomeAssistantService,
+    client: reqwest::Client,
+    /// Service data per device, merged over the data of the service call
+    device_data: HashMap<String, HashMap<String, Value>>,
+    /// Entities whose last decision was a grant
+    granted: Mutex<HashSet<String>>,
+}
+
+impl ServiceTrigger {
+    pub(crate) fn new(config: config::HomeAssistantService, client: reqwest::Client, devices: &HashMap<String, config::Device>) -> ServiceTrigger {
+        let device_data = devices.iter()
+            .filter_map(|(addr, device_config)| device_config.service_data.clone().map(|data| (addr.clone(), data)))
+            .collect();
+
+        ServiceTrigger {
+            config,
+            client,
+            device_data,
+            granted: Mutex::default(),
+        }
+    }
+
+    async fn call_service(&self, service_call: &config::ServiceCall, decision: &Decision) -> error::Result<()> {
+        let (domain, service) = service_call.service.split_once('.')
+            .ok_or(error::new(format!("service {} is not in the form domain.service", service_call.service)))?;
+
+        let mut data = service_call.data.clone();
+        if let Some(device_data) = self.device_data.get(&decision.device) {
+            data.extend(device_data.clone());
+        }
+
+        let url = format!("{}services/{}/{}", self.config.url, urlencoding::encode(domain), urlencoding::encode(service));
+        debug!("Calling service {} for {}", service_call.service, decision.device);
+
+        self.client.post(url)
+            .bearer_auth(self.config.token.clone())
+            .json(&data)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not call home assistant service {}: {:?}", service_call.service, e)))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for ServiceTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        if decision.outcome != Outcome::Granted {
+            self.granted.lock().unwrap().remove(&decision.entity);
+        }
+
+        match (decision.outcome, &self.config.off) {
+            (Outcome::Granted, _) => {
+                if self.granted.lock().unwrap().contains(&decision.entity) {
+                    return Ok(());
+                }
+
+                self.call_service(&self.config.on, decision).await?;
+                self.granted.lock().unwrap().insert(decision.entity.clone());
+
+                Ok(())
+            }
+            (Outcome::Released, Some(off)) => self.call_service(off, decision).await,
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Shelly relays through their local HTTP API.
+//!
+//! The first grant switches the relay on, using the timer of the relay if
+//! `auto_off` is configured, and it is switched off once the last granted
+//! device is released. Denials are ignored. The relay state is read back, so a
+//! relay which did not switch counts as a failed delivery. Gen1 devices are
+//! switched through `/relay` with basic auth, Gen2 devices through the RPC API
+//! with digest auth, where the username defaults to `admin`.
+
+use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+
+use async_trait::async_trait;
+use log::debug;
+use reqwest::{header, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{error, config};
+
+use super::{Decision, Holders, TriggerBackend};
+
+const DEFAULT_GENERATION: u8 = 1;
+
+/// Only user of Gen2 devices
+const DEFAULT_GEN2_USERNAME: &str = "admin";
+
+#[derive(Debug, Deserialize)]
+struct RelayStatus {
+    ison: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SwitchStatus {
+    output: bool,
+}
+
+pub(crate) struct ShellyTrigger {
+    config: config::Shelly,
+    generation: u8,
+    client: reqwest::Client,
+    /// Granted entities holding the relay on
+    holders: Holders<()>,
+}
+
+/// Parameters of a `WWW-Authenticate: Digest ...` challenge
+fn get_challenge(header: &str) -> Option<HashMap<String, String>> {
+    let (scheme, params) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("digest") {
+        return None;
+    }
+
+    Some(params.split(',')
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().trim_matches('"').to_string()))
+        .collect())
+}
+
+fn sha256(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+/// `Authorization` header answering a SHA-256 digest challenge as of RFC 7616
+fn get_authorization(username: &str, password: &str, challenge: &HashMap<String, String>, method: &str, uri: &str, cnonce: &str) -> error::Result<String> {
+    let algorithm = challenge.get("algorithm").map(String::as_str).unwrap_or("MD5");
+    if !algorithm.eq_ignore_ascii_case("SHA-256") {
+        return Err(error::new(format!("unsupported shelly digest algorithm {}", algorithm)));
+    }
+
+    let (realm, nonce) = match (challenge.get("realm"), challenge.get("nonce")) {
+        (Some(realm), Some(nonce)) => (realm, nonce),
+        _ => return Err(error::new("incomplete shelly digest challenge".to_string())),
+    };
+
+    let nc = "00000001";
+    let ha1 = sha256(&format!("{}:{}:{}", username, realm, password));
+    let ha2 = sha256(&format!("{}:{}", method, uri));
+    let response = sha256(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+
+    let mut authorization = format!(
+        r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=SHA-256, qop=auth, nc={}, cnonce="{}", response="{}""#,
+        username, realm, nonce, uri, nc, cnonce, response
+    );
+    if let Some(opaque) = challenge.get("opaque") {
+        authorization.push_str(&format!(r#", opaque="{}""#, opaque));
+    }
+
+    Ok(authorization)
+}
+
+impl ShellyTrigger {
+    pub(crate) fn new(config: config::Shelly, client: reqwest::Client) -> error::Result<ShellyTrigger> {
+        let generation = config.generation.unwrap_or(DEFAULT_GENERATION);
+        if !(1..=2).contains(&generation) {
+            return Err(error::new(format!("unsupported shelly generation {}", generation)));
+        }
+
+        Ok(ShellyTrigger {
+            config,
+            generation,
+            client,
+            holders: Holders::default(),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: String) -> error::Result<T> {
+        let url = format!("{}{}", self.config.url.trim_end_matches('/'), path);
+        debug!("Calling URL: {}", url);
+
+        let mut request = self.client.get(&url);
+        if let (1, Some(username)) = (self.generation, &self.config.username) {
+            request = request.basic_auth(username, self.config.password.clone());
+        }
+
+        let mut res = request.send().await;
+
+        // Gen2 devices challenge every request for digest auth
+        if let (2, Some(password), Ok(challenged)) = (self.generation, &self.config.password, &res) {
+            let challenge = challenged.headers().get(header::WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(get_challenge);
+
+            if let (StatusCode::UNAUTHORIZED, Some(challenge)) = (challenged.status(), challenge) {
+                let username = self.config.username.as_deref().unwrap_or(DEFAULT_GEN2_USERNAME);
+                let cnonce = format!("{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+                let authorization = get_authorization(username, password, &challenge, "GET", &path, &cnonce)?;
+
+                res = self.client.get(&url)
+                    .header(header::AUTHORIZATION, authorization)
+                    .send()
+                    .await;
+            }
+        }
+
+        res.and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not call shelly: {:?}", e)))?
+            .json()
+            .await
+            .map_err(|e| error::new(format!("invalid response from shelly: {:?}", e)))
+    }
+
+    async fn switch(&self, on: bool) -> error::Result<()> {
+        let channel = self.config.channel.unwrap_or(0);
+
+        let is_on = if self.generation == 1 {
+            let mut path = format!("/relay/{}?turn={}", channel, if on { "on" } else { "off" });
+            if let (true, Some(auto_off)) = (on, self.config.auto_off) {
+                path.push_str(&format!("&timer={}", auto_off));
+            }
+
+            self.get::<RelayStatus>(path).await?.ison
+        } else {
+            let mut path = format!("/rpc/Switch.Set?id={}&on={}", channel, on);
+            if let (true, Some(auto_off)) = (on, self.config.auto_off) {
+                path.push_str(&format!("&toggle_after={}", auto_off));
+            }
+
+            // Switch.Set only returns the previous state
+            self.get::<serde_json::Value>(path).await?;
+            self.get::<SwitchStatus>(format!("/rpc/Switch.GetStatus?id={}", channel)).await?.output
+        };
+
+        if is_on != on {
+            return Err(error::new(format!("shelly relay {} did not switch {}", channel, if on { "on" } else { "off" })));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for ShellyTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        self.holders.switch((), decision, |on| self.switch(on)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use crate::trigger::get_http_client;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    const REALM: &str = "shellyplus1-a8032ab12345";
+    const NONCE: &str = "60dc59c6";
+
+    /// Stand-in device answering every request with the body for its path and
+    /// query, or else for its path. With a password every request has to answer
+    /// a digest challenge. Counts the answered requests.
+    async fn serve(bodies: HashMap<&'static str, &'static str>, password: Option<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let answered = Arc::new(AtomicUsize::new(0));
+
+        let counter = answered.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                let request = String::from_utf8(request).unwrap();
+                let target = request.split(' ').nth(1).unwrap();
+                let authorization = request.lines()
+                    .find_map(|line| line.strip_prefix("authorization: "))
+                    .and_then(get_challenge);
+
+                let authorized = match (password, authorization) {
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                    (Some(password), Some(params)) => {
+                        let ha1 = sha256(&format!("admin:{}:{}", REALM, password));
+                        let ha2 = sha256(&format!("GET:{}", target));
+                        let expected = sha256(&format!("{}:{}:{}:{}:auth:{}", ha1, NONCE, params["nc"], params["cnonce"], ha2));
+                        params["uri"] == target && params["response"] == expected
+                    }
+                };
+
+                let response = if authorized {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let body = bodies.get(target).unwrap_or_else(|| &bodies[target.split('?').next().unwrap()]);
+                    format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                } else {
+                    format!("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Digest qop=\"auth\", realm=\"{}\", nonce=\"{}\", algorithm=SHA-256\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", REALM, NONCE)
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, answered)
+    }
+
+    fn shelly(url: String, generation: u8, password: Option<&str>) -> ShellyTrigger {
+        let config = config::Shelly {
+            url,
+            channel: None,
+            generation: Some(generation),
+            auto_off: None,
+            username: None,
+            password: password.map(str::to_string),
+            tls: None,
+        };
+
+        ShellyTrigger::new(config, get_http_client(None).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn gen1_relay_is_read_back() {
+        let (url, _) = serve(HashMap::from([("/relay/0", r#"{"ison":true}"#)]), None).await;
+
+        assert!(shelly(url.clone(), 1, None).switch(true).await.is_ok());
+        assert!(shelly(url, 1, None).switch(false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn gen2_switch_is_read_back() {
+        let (url, _) = serve(HashMap::from([
+            ("/rpc/Switch.Set", r#"{"was_on":true}"#),
+            ("/rpc/Switch.GetStatus", r#"{"id":0,"output":false}"#),
+        ]), None).await;
+
+        // Switch.Set answers with the previous state, which must not count
+        assert!(shelly(url.clone(), 2, None).switch(true).await.is_err());
+        assert!(shelly(url, 2, None).switch(false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gen2_answers_digest_challenge() {
+        let (url, answered) = serve(HashMap::from([
+            ("/rpc/Switch.Set", r#"{"was_on":true}"#),
+            ("/rpc/Switch.GetStatus", r#"{"id":0,"output":false}"#),
+        ]), Some("secret")).await;
+
+        assert!(shelly(url.clone(), 2, Some("secret")).switch(false).await.is_ok());
+        assert_eq!(answered.load(Ordering::SeqCst), 2);
+
+        let err = shelly(url, 2, Some("wrong")).switch(false).await.unwrap_err();
+        assert!(err.to_string().contains("401"), "{}", err);
+    }
+
+    #[test]
+    fn digest_matches_rfc_7616() {
+        let challenge = get_challenge(r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=SHA-256, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#).unwrap();
+        let authorization = get_authorization("Mufasa", "Circle of Life", &challenge, "GET", "/dir/index.html", "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ").unwrap();
+
+        assert!(authorization.contains(r#"response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1""#), "{}", authorization);
+        assert!(authorization.ends_with(r#"opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#), "{}", authorization);
+    }
+
+    #[tokio::test]
+    async fn relay_follows_granted_entities() {
+        let (url, answered) = serve(HashMap::from([
+            ("/relay/0?turn=on", r#"{"ison":true}"#),
+            ("/relay/0?turn=off", r#"{"ison":false}"#),
+        ]), None).await;
+        let trigger = shelly(url, 1, None);
+
+        let decision = |device: &str| {
+            let mut decision = Decision::new(device.to_string(), "door".to_string(), format!("lock.{}", device));
+            decision.known = true;
+            decision
+        };
+
+        // Repeated grants neither switch again nor restart the timer
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("b").grant()).await.unwrap();
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+
+        // The relay stays on until the last granted device is released
+        trigger.publish(&decision("a").release("absent")).await.unwrap();
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+        trigger.publish(&decision("b").release("absent")).await.unwrap();
+        assert_eq!(answered.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,203 @@
+//! Tasmota relays through the `cm` command endpoint.
+//!
+//! The first grant switches the relay on and it is switched off once the last
+//! granted device is released, denials are ignored. With `auto_off` the
+//! `PulseTime` of the relay is set before switching on, so the relay switches
+//! itself off again. The relay state in the response is
+//! checked, so a relay which did not switch counts as a failed delivery.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use log::debug;
+use serde_json::Value;
+
+use crate::{error, config};
+
+use super::{Decision, Holders, TriggerBackend};
+
+const DEFAULT_RELAY: u8 = 1;
+
+pub(crate) struct TasmotaTrigger {
+    config: config::Tasmota,
+    client: reqwest::Client,
+    /// Granted entities holding the relay on
+    holders: Holders<()>,
+}
+
+/// `PulseTime` counts in tenths of a second up to 111, above in seconds plus 100
+fn get_pulse_time(seconds: u64) -> u64 {
+    if seconds * 10 <= 111 {
+        seconds * 10
+    } else {
+        seconds + 100
+    }
+}
+
+impl TasmotaTrigger {
+    pub(crate) fn new(config: config::Tasmota, client: reqwest::Client) -> TasmotaTrigger {
+        TasmotaTrigger {
+            config,
+            client,
+            holders: Holders::default(),
+        }
+    }
+
+    async fn command(&self, command: String) -> error::Result<HashMap<String, Value>> {
+        let url = format!("{}/cm", self.config.url.trim_end_matches('/'));
+        debug!("Sending {} to {}", command, url);
+
+        let mut query = vec![("cmnd", command)];
+        if let Some(username) = &self.config.username {
+            query.push(("user", username.clone()));
+            query.push(("password", self.config.password.clone().unwrap_or_default()));
+        }
+
+        self.client.get(url)
+            .query(&query)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not call tasmota: {:?}", e)))?
+            .json()
+            .await
+            .map_err(|e| error::new(format!("invalid response from tasmota: {:?}", e)))
+    }
+
+    async fn switch(&self, on: bool) -> error::Result<()> {
+        let relay = self.config.relay.unwrap_or(DEFAULT_RELAY);
+        let state = if on { "ON" } else { "OFF" };
+
+        if let (true, Some(auto_off)) = (on, self.config.auto_off) {
+            self.command(format!("PulseTime{} {}", relay, get_pulse_time(auto_off))).await?;
+        }
+
+        let response = self.command(format!("Power{} {}", relay, state)).await?;
+
+        // Devices with a single relay answer with POWER instead of POWER1
+        let is_state = response.get(&format!("POWER{}", relay))
+            .or(response.get("POWER"))
+            .and_then(|value| value.as_str())
+            .is_some_and(|value| value == state);
+        if !is_state {
+            return Err(error::new(format!("tasmota relay {} did not switch {}", relay, state)));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for TasmotaTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        self.holders.switch((), decision, |on| self.switch(on)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::trigger::get_http_client;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    /// Stand-in device answering every command with its body, `{}` for
+    /// unknown ones. Records the received commands.
+    async fn serve(bodies: HashMap<&'static str, &'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let commands = Arc::new(Mutex::new(Vec::new()));
+
+        let received = commands.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                let request = String::from_utf8(request).unwrap();
+                let query = request.split(' ').nth(1).unwrap().strip_prefix("/cm?cmnd=").unwrap();
+                let command = urlencoding::decode(&query.replace('+', " ")).unwrap().into_owned();
+
+                let body = bodies.get(command.as_str()).copied().unwrap_or("{}");
+                received.lock().unwrap().push(command);
+
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, commands)
+    }
+
+    fn tasmota(url: String, relay: u8, auto_off: Option<u64>) -> TasmotaTrigger {
+        let config = config::Tasmota {
+            url,
+            relay: Some(relay),
+            auto_off,
+            username: None,
+            password: None,
+            tls: None,
+        };
+
+        TasmotaTrigger::new(config, get_http_client(None).unwrap())
+    }
+
+    fn decision(device: &str) -> Decision {
+        let mut decision = Decision::new(device.to_string(), "door".to_string(), format!("lock.{}", device));
+        decision.known = true;
+        decision
+    }
+
+    #[test]
+    fn pulse_time_switches_to_seconds_above_11s() {
+        assert_eq!(get_pulse_time(1), 10);
+        assert_eq!(get_pulse_time(11), 110);
+        assert_eq!(get_pulse_time(12), 112);
+        assert_eq!(get_pulse_time(60), 160);
+    }
+
+    #[tokio::test]
+    async fn relay_state_is_read_back() {
+        let (url, _) = serve(HashMap::from([
+            // Single relay devices answer with POWER
+            ("Power1 ON", r#"{"POWER":"ON"}"#),
+            ("Power1 OFF", r#"{"POWER":"ON"}"#),
+            ("Power2 ON", r#"{"POWER2":"ON"}"#),
+            ("Power2 OFF", r#"{"POWER1":"OFF","POWER2":"ON"}"#),
+        ])).await;
+
+        assert!(tasmota(url.clone(), 1, None).switch(true).await.is_ok());
+        assert!(tasmota(url.clone(), 1, None).switch(false).await.is_err());
+        assert!(tasmota(url.clone(), 2, None).switch(true).await.is_ok());
+        // Another relay switched off must not count
+        assert!(tasmota(url, 2, None).switch(false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_follows_granted_entities() {
+        let (url, commands) = serve(HashMap::from([
+            ("Power1 ON", r#"{"POWER1":"ON"}"#),
+            ("Power1 OFF", r#"{"POWER1":"OFF"}"#),
+        ])).await;
+        let trigger = tasmota(url, 1, Some(5));
+
+        // Repeated grants neither switch again nor restart the pulse
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("a").grant()).await.unwrap();
+        trigger.publish(&decision("b").grant()).await.unwrap();
+        trigger.publish(&decision("a").release("absent")).await.unwrap();
+        assert_eq!(*commands.lock().unwrap(), ["PulseTime1 50", "Power1 ON"]);
+
+        trigger.publish(&decision("b").release("absent")).await.unwrap();
+        assert_eq!(*commands.lock().unwrap(), ["PulseTime1 50", "Power1 ON", "Power1 OFF"]);
+    }
+}
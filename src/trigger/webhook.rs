@@ -0,0 +1,121 @@
+//! Generic outbound webhook.
+//!
+//! The body is built from a JSON template whose strings can contain `{{field}}`
+//! placeholders for the decision fields. A string consisting only of a placeholder
+//! is replaced by the field value itself, so numbers and `null` keep their type.
+//! Without a template the whole decision is sent. If a secret is configured, the
+//! body is signed with HMAC-SHA256 and the signature is sent as `sha256=<hex>`.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use log::debug;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{error, config};
+
+use super::{Decision, TriggerBackend};
+
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Ble-Fencer-Signature";
+
+pub(crate) struct WebhookTrigger {
+    config: config::Webhook,
+    method: reqwest::Method,
+    client: reqwest::Client,
+}
+
+fn render(template: &Value, fields: &serde_json::Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            // A single placeholder keeps the type of the field
+            if let Some(field) = text.strip_prefix("{{").and_then(|text| text.strip_suffix("}}")) {
+                if let Some(value) = fields.get(field.trim()) {
+                    return value.clone();
+                }
+            }
+
+            let mut rendered = text.clone();
+            for (field, value) in fields {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Null => String::new(),
+                    value => value.to_string(),
+                };
+
+                rendered = rendered.replace(&format!("{{{{{}}}}}", field), &value);
+            }
+
+            Value::String(rendered)
+        }
+        Value::Array(values) => Value::Array(values.iter().map(|value| render(value, fields)).collect()),
+        Value::Object(values) => Value::Object(values.iter().map(|(key, value)| (key.clone(), render(value, fields))).collect()),
+        value => value.clone(),
+    }
+}
+
+impl WebhookTrigger {
+    pub(crate) fn new(config: config::Webhook, client: reqwest::Client) -> error::Result<WebhookTrigger> {
+        let method = reqwest::Method::from_bytes(config.method.clone().unwrap_or("POST".to_string()).to_uppercase().as_bytes())
+            .map_err(|e| error::new(format!("invalid webhook method: {}", e)))?;
+
+        Ok(WebhookTrigger {
+            config,
+            method,
+            client,
+        })
+    }
+
+    fn get_body(&self, decision: &Decision) -> error::Result<String> {
+        let mut fields = match serde_json::to_value(decision) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return Err(error::new("could not serialize decision".to_string())),
+        };
+        fields.insert("timestamp".to_string(), json!(chrono::Utc::now().timestamp()));
+
+        let body = match &self.config.body {
+            Some(template) => render(template, &fields),
+            None => Value::Object(fields),
+        };
+
+        Ok(body.to_string())
+    }
+}
+
+#[async_trait]
+impl TriggerBackend for WebhookTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        let body = self.get_body(decision)?;
+        let mut headers: HashMap<String, String> = self.config.headers.clone();
+
+        if let Some(secret) = &self.config.secret {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .map_err(|e| error::new(format!("invalid webhook secret: {}", e)))?;
+            mac.update(body.as_bytes());
+
+            let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+            headers.insert(self.config.signature_header.clone().unwrap_or(DEFAULT_SIGNATURE_HEADER.to_string()), signature);
+        }
+
+        debug!("Calling webhook {} for {}", self.config.url, decision.device);
+
+        let mut request = self.client.request(self.method.clone(), &self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+
+        request.send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| error::new(format!("could not call webhook: {:?}", e)))?;
+
+        Ok(())
+    }
+}
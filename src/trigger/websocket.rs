@@ -0,0 +1,304 @@
+//! Persistent connection to the Home Assistant WebSocket API.
+//!
+//! The connection authenticates once and is reestablished with a backoff, e.g.
+//! when Home Assistant restarts. Decisions of known devices are fired as events
+//! over it when their outcome or reason changes, and the states of watched
+//! entities (mode helpers, zone conditions) are kept up to date for use in
+//! decisions.
+//!
+//! The `entity_id` of events is rendered from the same templates as the REST
+//! backend uses, so both refer to the same entity.
+
+use std::{collections::{HashMap, HashSet}, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{Connector, tungstenite::Message};
+
+use crate::{error, config};
+
+use super::{Decision, HomeAssistantStates, Outcome, TriggerBackend, home_assistant::{DEFAULT_ENTITY_ID, get_entity_id}};
+
+const DEFAULT_EVENT_TYPE: &str = "ble_fencer_state";
+
+/// Maximum seconds to wait between reconnects
+const MAX_BACKOFF: u64 = 60;
+
+/// Commands which are queued while Home Assistant is unreachable
+const COMMAND_QUEUE: usize = 100;
+
+pub(crate) struct WebsocketTrigger {
+    event_type: String,
+    /// Entity id template of devices without their own
+    entity_id: String,
+    /// Entity id templates per device
+    entity_ids: HashMap<String, String>,
+    /// Outcome and reason of the last event per entity
+    fired: Mutex<HashMap<String, (Outcome, Option<String>)>>,
+    commands: mpsc::Sender<Value>,
+}
+
+fn get_text(message: Message) -> Option<String> {
+    match message {
+        Message::Text(text) => Some(text),
+        _ => None,
+    }
+}
+
+fn get_connector(tls: &config::Tls) -> error::Result<Connector> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(ca_file) = &tls.ca_file {
+        for pem in super::read_certificates(ca_file)? {
+            let certificate = native_tls::Certificate::from_pem(&pem)
+                .map_err(|e| error::new(format!("invalid certificate in {}: {}", ca_file, e)))?;
+            builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some((cert, key)) = super::read_identity(tls)? {
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .map_err(|e| error::new(format!("invalid client certificate or key: {}", e)))?;
+        builder.identity(identity);
+    }
+
+    if tls.insecure {
+        warn!("TLS certificates are not verified, only use this for testing");
+        builder.danger_accept_invalid_certs(true);
+    }
+
+    let connector = builder.build()
+        .map_err(|e| error::new(format!("could not create TLS connector: {}", e)))?;
+
+    Ok(Connector::NativeTls(connector))
+}
+
+async fn serve(config: &config::HomeAssistantWebsocket, connector: &Option<Connector>, states: &HomeAssistantStates, watched: &HashSet<String>, commands: &mut mpsc::Receiver<Value>, backoff: &mut u64) -> error::Result<()> {
+    let (socket, _) = tokio_tungstenite::connect_async_tls_with_config(config.url.as_str(), None, false, connector.clone())
+        .await
+        .map_err(|e| error::new(format!("could not connect to home assistant: {}", e)))?;
+    let (mut write, mut read) = socket.split();
+
+    // Authenticate once for the whole connection
+    let mut authenticated = false;
+    while !authenticated {
+        let message = read.next()
+            .await
+            .ok_or(error::new("home assistant closed the connection during auth".to_string()))?
+            .map_err(|e| error::new(format!("could not read from home assistant: {}", e)))?;
+
+        let value: Value = match get_text(message) {
+            Some(text) => serde_json::from_str(&text)
+                .map_err(|e| error::new(format!("invalid message from home assistant: {}", e)))?,
+            None => continue,
+        };
+
+        match value["type"].as_str() {
+            Some("auth_required") => {
+                let auth = json!({ "type": "auth", "access_token": config.token });
+                write.send(Message::Text(auth.to_string()))
+                    .await
+                    .map_err(|e| error::new(format!("could not send auth to home assistant: {}", e)))?;
+            }
+            Some("auth_ok") => authenticated = true,
+            Some("auth_invalid") => return Err(error::new(format!("home assistant rejected the token: {}", value["message"]))),
+            _ => (),
+        }
+    }
+
+    info!("Connected to Home Assistant WebSocket API");
+    *backoff = 1;
+
+    let mut id = 1;
+    let get_states_id = id + 1;
+    for command in [json!({ "type": "subscribe_events", "event_type": "state_changed" }), json!({ "type": "get_states" })] {
+        let mut command = command;
+        command["id"] = json!(id);
+        id += 1;
+
+        write.send(Message::Text(command.to_string()))
+            .await
+            .map_err(|e| error::new(format!("could not send to home assistant: {}", e)))?;
+    }
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                let message = message
+                    .ok_or(error::new("home assistant closed the connection".to_string()))?
+                    .map_err(|e| error::new(format!("could not read from home assistant: {}", e)))?;
+
+                let text = match get_text(message) {
+                    Some(text) => text,
+                    None => continue,
+                };
+
+                let value: Value = serde_json::from_str(&text)
+                    .map_err(|e| error::new(format!("invalid message from home assistant: {}", e)))?;
+
+                match value["type"].as_str() {
+                    Some("event") => {
+                        let data = &value["event"]["data"];
+                        if let (Some(entity_id), Some(state)) = (data["entity_id"].as_str(), data["new_state"]["state"].as_str()) {
+                            if watched.contains(entity_id) {
+                                debug!("{} changed to {}", entity_id, state);
+                                states.write().unwrap().insert(entity_id.to_string(), state.to_string());
+                            }
+                        }
+                    }
+                    Some("result") if value["id"] == json!(get_states_id) => {
+                        for entity in value["result"].as_array().into_iter().flatten() {
+                            if let (Some(entity_id), Some(state)) = (entity["entity_id"].as_str(), entity["state"].as_str()) {
+                                if watched.contains(entity_id) {
+                                    states.write().unwrap().insert(entity_id.to_string(), state.to_string());
+                                }
+                            }
+                        }
+                    }
+                    Some("result") if value["success"] == json!(false) => {
+                        warn!("Home Assistant command {} failed: {}", value["id"], value["error"]);
+                    }
+                    _ => (),
+                }
+            }
+            command = commands.recv() => {
+                let mut command = match command {
+                    Some(command) => command,
+                    None => return Ok(()),
+                };
+
+                command["id"] = json!(id);
+                id += 1;
+
+                write.send(Message::Text(command.to_string()))
+                    .await
+                    .map_err(|e| error::new(format!("could not send to home assistant: {}", e)))?;
+            }
+        }
+    }
+}
+
+async fn run(config: config::HomeAssistantWebsocket, connector: Option<Connector>, states: HomeAssistantStates, watched: HashSet<String>, mut commands: mpsc::Receiver<Value>) {
+    let mut backoff = 1;
+
+    loop {
+        match serve(&config, &connector, &states, &watched, &mut commands, &mut backoff).await {
+            Ok(()) => return,
+            Err(err) => {
+                warn!("Home Assistant WebSocket failed, reconnecting in {}s: {}", backoff, err);
+
+                // Watched states are unknown until we are connected again
+                states.write().unwrap().clear();
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl WebsocketTrigger {
+    pub(crate) fn new(config: config::HomeAssistantWebsocket, states: HomeAssistantStates, watched: HashSet<String>, devices: &HashMap<String, config::Device>) -> error::Result<WebsocketTrigger> {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE);
+        let event_type = config.event_type.clone().unwrap_or(DEFAULT_EVENT_TYPE.to_string());
+        let entity_id = config.entity_id.clone().unwrap_or(DEFAULT_ENTITY_ID.to_string());
+        let entity_ids = devices.iter()
+            .filter_map(|(addr, device_config)| device_config.entity_id.clone().map(|entity_id| (addr.clone(), entity_id)))
+            .collect();
+        let connector = match &config.tls {
+            Some(tls) => Some(get_connector(tls)?),
+            None => None,
+        };
+
+        tokio::spawn(run(config, connector, states, watched, receiver));
+
+        Ok(WebsocketTrigger {
+            event_type,
+            entity_id,
+            entity_ids,
+            fired: Mutex::default(),
+            commands,
+        })
+    }
+}
+
+/// Returns the first entity whose state does not match the required one
+pub(crate) fn get_unmet_condition(conditions: &HashMap<String, String>, states: &HomeAssistantStates) -> Option<String> {
+    let states = states.read().unwrap();
+
+    conditions.iter()
+        .find(|(entity, state)| states.get(*entity) != Some(*state))
+        .map(|(entity, _)| entity.clone())
+}
+
+#[async_trait]
+impl TriggerBackend for WebsocketTrigger {
+    async fn publish(&self, decision: &Decision) -> error::Result<()> {
+        if !decision.known {
+            return Ok(());
+        }
+
+        // Every frame of a tag is a decision, events are only fired for changes
+        let fired = (decision.outcome, decision.reason.clone());
+        if self.fired.lock().unwrap().get(&decision.entity) == Some(&fired) {
+            return Ok(());
+        }
+
+        let state = match decision.outcome {
+            Outcome::Granted => "on",
+            Outcome::Denied | Outcome::Released => "off",
+        };
+
+        let mut event_data = serde_json::to_value(decision)
+            .map_err(|e| error::new(format!("could not serialize decision: {}", e)))?;
+        let template = self.entity_ids.get(&decision.device).unwrap_or(&self.entity_id);
+        event_data["entity_id"] = json!(get_entity_id(template, decision));
+        event_data["state"] = json!(state);
+
+        let command = json!({
+            "type": "fire_event",
+            "event_type": self.event_type,
+            "event_data": event_data,
+        });
+
+        self.commands.try_send(command)
+            .map_err(|e| error::new(format!("could not queue event for home assistant: {}", e)))?;
+
+        self.fired.lock().unwrap().insert(decision.entity.clone(), fired);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn events_are_only_fired_for_changes() {
+        let (commands, mut receiver) = mpsc::channel(COMMAND_QUEUE);
+        let trigger = WebsocketTrigger {
+            event_type: DEFAULT_EVENT_TYPE.to_string(),
+            entity_id: DEFAULT_ENTITY_ID.to_string(),
+            entity_ids: HashMap::new(),
+            fired: Mutex::default(),
+            commands,
+        };
+
+        let mut decision = Decision::new("AA:BB:CC:DD:EE:FF".to_string(), "door".to_string(), "AA:BB:CC:DD:EE:FF".to_string());
+        decision.known = true;
+
+        for decision in [decision.clone().grant(), decision.clone().grant(), decision.clone().deny("lockdown"), decision.clone().deny("lockdown"), decision.clone().deny("passback")] {
+            trigger.publish(&decision).await.unwrap();
+        }
+
+        let mut reasons = Vec::new();
+        while let Ok(command) = receiver.try_recv() {
+            assert_eq!(command["event_data"]["entity_id"], "binary_sensor.aa_bb_cc_dd_ee_ff");
+            reasons.push(command["event_data"]["reason"].clone());
+        }
+        assert_eq!(reasons, [Value::Null, json!("lockdown"), json!("passback")]);
+    }
+}
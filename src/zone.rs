@@ -0,0 +1,70 @@
+//! Access zones served by this controller.
+//!
+//! Every zone has its own adapter, RSSI threshold, trigger entity and schedule.
+//! If no zones are configured, a single implicit zone covers the default adapter
+//! and triggers the plain per-device entities.
+
+use std::collections::HashMap;
+
+use crate::{error, config};
+
+pub(crate) const DEFAULT_ZONE: &str = "default";
+
+/// Default seconds in which the valid frames of multiple persons have to be seen
+pub(crate) const DEFAULT_MIN_PERSONS_WINDOW: u64 = 10;
+
+pub(crate) fn get_zones(config: &config::Config) -> HashMap<String, config::Zone> {
+    if !config.zones.is_empty() {
+        return config.zones.clone();
+    }
+
+    let mut zones = HashMap::new();
+    zones.insert(DEFAULT_ZONE.to_string(), config::Zone {
+        adapter: None,
+        cutoff_rssi: None,
+        entity: Some(String::new()),
+        allowed_times: None,
+        time_zone: None,
+        direction: None,
+        anti_passback: false,
+        anti_passback_grace: None,
+        min_persons: None,
+        min_persons_window: None,
+        triggers: None,
+        conditions: HashMap::new(),
+        pulse: None,
+        failover: None,
+    });
+
+    zones
+}
+
+/// Name of the entity which is triggered for a device inside the zone
+pub(crate) fn get_entity(zone_name: &str, zone: &config::Zone, device: &str) -> String {
+    let prefix = zone.entity.clone().unwrap_or(zone_name.to_string());
+    if prefix.is_empty() {
+        device.to_string()
+    } else {
+        format!("{}_{}", prefix, device)
+    }
+}
+
+/// Checks if the device is granted inside the zone
+pub(crate) fn is_granted(zone_name: &str, device_config: &config::Device) -> bool {
+    match &device_config.zones {
+        Some(zones) => zones.iter().any(|granted_zone| granted_zone == zone_name),
+        None => true,
+    }
+}
+
+pub(crate) async fn validate(config: &config::Config) -> error::Result<()> {
+    for (addr, device_config) in config.devices.iter() {
+        for zone_name in device_config.zones.iter().flatten() {
+            if !config.zones.contains_key(zone_name) {
+                return Err(error::new(format!("{} is granted for unknown zone {}", addr, zone_name)));
+            }
+        }
+    }
+
+    Ok(())
+}